@@ -0,0 +1,64 @@
+// allocator.rs --- allocator-api2 support for the dense heap.
+
+// Copyright (c) 2023 Sam Belliveau. All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use core::{
+    alloc::Layout,
+    mem::{align_of, size_of},
+    ptr::NonNull,
+};
+
+use allocator_api2::alloc::{AllocError, Allocator, Global};
+
+use crate::{dheap::DHeap, index::DIndex};
+
+// Returns true if a block with the given layout fits into the slot of a T.
+fn fits<T>(layout: Layout) -> bool {
+    layout.size() != 0 && layout.size() <= size_of::<T>() && layout.align() <= align_of::<T>()
+}
+
+/// A shared reference to a DHeap can be used as an `Allocator`, e.g. for `Box<T, &DHeap<T>>`.
+///
+/// Blocks whose layout fits into a `T` are served from the free list of the heap, one slot per block,
+/// and the slot stays taken until the block is deallocated. Like `DHeap::new()`, this never moves memory
+/// that is already in use. Every other layout, such as the buffer of a `Vec` that holds more than one
+/// value, or a zero-sized block, falls back to the global allocator, so the heap can back any container.
+///
+/// Lent slots are skipped by iteration and `get()`, and are counted by `DHeapStats::lent`.
+unsafe impl<T, I: DIndex> Allocator for &DHeap<T, I> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if !fits::<T>(layout) {
+            return Global.allocate(layout);
+        }
+
+        let block = self.lend().ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(block.cast(), layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // The layout decides where the block came from, just like it did in allocate().
+        if fits::<T>(layout) {
+            self.reclaim(ptr.cast());
+        } else {
+            Global.deallocate(ptr, layout)
+        }
+    }
+}
@@ -20,49 +20,156 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use std::{
+use alloc::{collections::TryReserveError, vec, vec::Vec};
+use core::{
+    borrow::{Borrow, BorrowMut},
     cell::{Cell, UnsafeCell},
-    hint::unreachable_unchecked,
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
     marker::PhantomData,
-    mem::{replace, ManuallyDrop},
+    mem::{self, forget, ManuallyDrop, MaybeUninit},
     ops::{Deref, DerefMut, Drop},
+    ptr::{self, drop_in_place, NonNull},
 };
 
-/// The DHeapNode contains all the metadata required to keep the DHeap organized.
-enum DHeapNode<T: Sized> {
-    /// Edge is always the last element of the vector. When the
-    /// head points to the edge, new memory must be allocated.
-    Edge(),
+#[cfg(feature = "track-allocations")]
+use core::panic::Location;
 
-    /// Empty represents a previously occupied slot that has
-    /// been freed. It points to the previous head when
-    /// it was freed, creating a chain of free blocks for
-    /// future allocations.
-    Empty(usize),
-
-    /// Holding represents a memory slot in use by a DBox<_>.
-    /// The memory is owned by the DBox<_> pointing to it,
-    /// which is why it is wrapped in a ManuallyDrop<_>. The DBox<_>
-    /// is guaranteed to drop before the DHeap<_>.
-    Holding(ManuallyDrop<T>),
+use crate::{
+    error::{AllocError, FreeError},
+    index::DIndex,
+    leak::{LeakPolicy, Sweeper},
+    rc::Counts,
+    slot::{not_holding, Slot, State, Tag},
+    storage::ChunkedVec,
+    validate::{DHeapIssue, DHeapReport},
+};
 
-    /// When calling DBox.into_inner(), memory is moved out of the
-    /// DHeap<_> before the DBox<_> has dropped. This serves as an indicator
-    /// for the DBox<_> not to panic when it finds its memory moved during
-    /// the dropping process.
-    Moved(),
+/// A DKey is a lightweight handle to a value stored in a DHeap with `DHeap::insert()`.
+///
+/// Unlike a DBox, a DKey does not borrow the heap and does not own the value. It is a plain
+/// index paired with the generation of the slot, so it can be copied freely, stored in other
+/// data structures and sent across threads. Once the value is removed, the slot's generation
+/// changes, and any remaining copies of the key stop resolving, even after the slot is reused.
+///
+/// A key is as wide as the index type `I` of its heap, plus 4 bytes for the generation.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DKey<I: DIndex = usize> {
+    index: I,
+    generation: u32,
 }
 
-use DHeapNode::*;
+impl<I: DIndex> DKey<I> {
+    /// Returns the index of the slot this key points to.
+    pub fn index(&self) -> usize {
+        self.index.to_usize()
+    }
+
+    /// Returns the generation of the slot at the time the key was created.
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
 
 /// A DHeap is a dense heap data structure that efficiently manages memory allocation and deallocation.
 ///
-/// The heap has an overhead of 24 bytes per element, and it will never use more memory than what is allocated
-/// at any given point in time, no matter which elements are freed and in which order. The linking nature of the
-/// indices will always backfill optimally, ensuring that the memory usage is as efficient as possible.
-pub struct DHeap<T: Sized> {
-    buffer: UnsafeCell<Vec<DHeapNode<T>>>,
+/// Values share their memory with the link of the free list, so every slot takes the size of the larger of `T` and the
+/// index type `I`, plus a 4-byte tag that holds the state and generation of the slot. The index type defaults to `usize`,
+/// so on 64-bit targets a `DHeap<i32>` takes 8 + 4 bytes per element. It can be narrowed down to `u32`, `u16` or `u8`
+/// for heaps that never need to address that many elements, see `DIndex`, which brings a `DHeap<i32, u32>` down to
+/// 4 + 4 bytes. Heaps that share values through `DRc` handles keep their reference counts in a separate column. It will never use more memory than what is allocated at any given point in time, no matter which
+/// elements are freed and in which order. The linking nature of the indices will always backfill optimally, ensuring
+/// that the memory usage is as efficient as possible.
+pub struct DHeap<T: Sized, I: DIndex = usize> {
+    buffer: NonNull<ChunkedVec<Slot<T, I>, Tag>>,
+    sweeper: Sweeper,
     head: Cell<usize>,
+    stats: Cell<DHeapStats>,
+    poisoned: Cell<bool>,
+    counts: UnsafeCell<Vec<Counts>>,
+
+    /// Values are written and read through `&self`, so the heap must be invariant in `T`, like a `Cell<T>`.
+    /// Otherwise a heap of long-lived references could be used to store short-lived ones. A function
+    /// pointer is used instead of a `Cell<T>`, which would make values that borrow their own heap
+    /// fail the drop check:
+    ///
+    /// ```compile_fail
+    /// use dense_heap::dheap::DHeap;
+    ///
+    /// fn shorten<'a>(heap: &'a DHeap<&'static str>) -> &'a DHeap<&'a str> {
+    ///     heap
+    /// }
+    /// ```
+    _variance: PhantomData<fn(T) -> T>,
+}
+
+// SAFETY: The heap owns its memory and the values in it, like a Vec<T> does, and the raw pointer
+// to the memory is never shared with another heap. The marker keeps the heap invariant in `T`,
+// and is always Send, so it does not change which heaps may be sent.
+unsafe impl<T: Send, I: DIndex> Send for DHeap<T, I> {}
+
+/// DHeapStats is a snapshot of the bookkeeping of a DHeap, returned by `DHeap::stats()`.
+///
+/// The counters are maintained as the heap is used, so taking a snapshot is cheap.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct DHeapStats {
+    /// The number of slots currently holding a value.
+    pub live: usize,
+
+    /// The number of freed slots waiting in the free list.
+    pub free: usize,
+
+    /// The number of slots whose value was moved out with `into_inner()`,
+    /// but whose DBox has not finished dropping yet.
+    pub moved: usize,
+
+    /// The number of slots that were taken without a value, by `alloc_uninit()` or the `Allocator` implementation.
+    pub lent: usize,
+
+    /// The number of slots whose value was leaked with `DBox::leak()`, which are never reused.
+    pub leaked: usize,
+
+    /// The number of values the heap can hold before it has to allocate more memory.
+    pub capacity: usize,
+
+    /// The highest number of values that were live at the same time.
+    pub peak: usize,
+
+    /// The total number of allocations made over the lifetime of the heap.
+    pub allocations: usize,
+
+    /// The total number of slots returned to the free list over the lifetime of the heap.
+    pub frees: usize,
+}
+
+impl DHeapStats {
+    /// Returns the share of used slots that sit in the free list, between 0 and 1.
+    ///
+    /// A fragmentation of 0 means that every slot below the `Edge` holds a value.
+    pub fn fragmentation(&self) -> f64 {
+        let used = self.live + self.free + self.moved + self.lent + self.leaked;
+
+        if used == 0 {
+            0.0
+        } else {
+            self.free as f64 / used as f64
+        }
+    }
+}
+
+/// Growth tells DHeap::alloc() what it may do when the free list is exhausted.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Growth {
+    /// Push onto the buffer, moving the nodes if the vector has to be resized.
+    InPlace,
+
+    /// Push onto the buffer, starting a new chunk instead of moving any nodes.
+    Stable,
+
+    /// Only use memory that has already been reserved.
+    Never,
 }
 
 impl<T> DHeap<T> {
@@ -71,6 +178,8 @@ impl<T> DHeap<T> {
     /// Allocates a buffer with the requested capacity, plus one additional element to account for the `Edge`.
     /// The `Edge` is a sentinel element used to facilitate certain heap operations.
     ///
+    /// The heap addresses its elements with a `usize`. Use `with_capacity_indexed()` for a narrower index.
+    ///
     /// # Arguments
     ///
     /// * `capacity` - The desired initial capacity for the heap.
@@ -79,22 +188,268 @@ impl<T> DHeap<T> {
     ///
     /// Panics if `capacity` is less than or equal to 1, as the heap requires at least 2 elements to function properly.
     pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_indexed(capacity)
+    }
+
+    /// Creates a new `DHeap` whose memory is split into fixed-size chunks.
+    ///
+    /// The heap addresses its elements with a `usize`. Use `with_chunk_size_indexed()` for a narrower index.
+    /// See `with_chunk_size_indexed()` for details.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is less than or equal to 1, as the heap requires at least 2 elements to function properly.
+    pub fn with_chunk_size(chunk_size: usize) -> Self {
+        Self::with_chunk_size_indexed(chunk_size)
+    }
+}
+
+impl<T, I: DIndex> DHeap<T, I> {
+    /// Creates a new `DHeap` with a specified initial capacity, addressed by the index type `I`.
+    ///
+    /// Allocates a buffer with the requested capacity, plus one additional element to account for the `Edge`.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - The desired initial capacity for the heap.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is less than or equal to 1, as the heap requires at least 2 elements to function properly.
+    pub fn with_capacity_indexed(capacity: usize) -> Self {
         assert!(capacity > 1);
 
+        // We add one more element than requested to account for the Edge.
+        Self::from_storage(ChunkedVec::flat(capacity + 1))
+    }
+
+    /// Creates a new `DHeap` whose memory is split into fixed-size chunks, addressed by the index type `I`.
+    ///
+    /// Every chunk holds `chunk_size` elements and is allocated exactly once. Growing the heap
+    /// appends a new chunk instead of resizing the existing memory, so values never move
+    /// and references into the heap remain valid for as long as their `DBox` lives.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunk_size` - The number of elements in each chunk, including the `Edge` in the last one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is less than or equal to 1, as the heap requires at least 2 elements to function properly.
+    pub fn with_chunk_size_indexed(chunk_size: usize) -> Self {
+        assert!(chunk_size > 1);
+
+        Self::from_storage(ChunkedVec::chunked(chunk_size))
+    }
+
+    fn from_storage(mut memory: ChunkedVec<Slot<T, I>, Tag>) -> Self {
+        memory.push(Slot::link(Self::link(0)), Tag::new(State::Edge, 0));
+        let (sweeper, buffer) = Sweeper::new(memory);
+
         DHeap {
-            buffer: {
-                // We add one more element than requested to account for the Edge.
-                let mut memory = Vec::with_capacity(capacity + 1);
-                memory.push(Edge());
-                memory.into()
-            },
+            buffer,
+            sweeper,
             head: Cell::new(0),
+            stats: Cell::new(DHeapStats::default()),
+            poisoned: Cell::new(false),
+            counts: UnsafeCell::new(Vec::new()),
+            _variance: PhantomData,
+        }
+    }
+
+    // Snapshots read every slot of the heap as it is, see snapshot.rs.
+    #[cfg(feature = "serde")]
+    pub(crate) fn raw_slot(&self, index: usize) -> (Tag, *mut Slot<T, I>) {
+        let (slot, tag) = self.memory().get(index);
+        (unsafe { *tag }, slot)
+    }
+
+    #[cfg(feature = "serde")]
+    pub(crate) fn raw_head(&self) -> usize {
+        self.head.get()
+    }
+
+    // Restores a heap from a snapshot. The storage must end in the Edge, and the head must
+    // point into it. The counters start over, as if every value had just been allocated.
+    //
+    // The restored heap is validated, and its values are dropped if it is broken.
+    #[cfg(feature = "serde")]
+    pub(crate) fn from_raw_parts(
+        mut memory: ChunkedVec<Slot<T, I>, Tag>,
+        head: usize,
+    ) -> Result<Self, DHeapIssue> {
+        let mut stats = DHeapStats::default();
+
+        for index in 0..memory.len() {
+            match unsafe { (*memory.get(index).1).state() } {
+                State::Holding | State::Keyed => stats.live += 1,
+                State::Empty => stats.free += 1,
+                State::Leaked => stats.leaked += 1,
+                _ => {}
+            }
+        }
+
+        stats.peak = stats.live;
+        stats.allocations = stats.live;
+
+        let (sweeper, buffer) = Sweeper::new(memory);
+
+        let mut heap = DHeap {
+            buffer,
+            sweeper,
+            head: Cell::new(head),
+            stats: Cell::new(stats),
+            poisoned: Cell::new(false),
+            counts: UnsafeCell::new(Vec::new()),
+            _variance: PhantomData,
+        };
+
+        match heap.validate().issues.into_iter().next() {
+            None => Ok(heap),
+            Some(issue) => {
+                // Every value in the heap was just deserialized, so nothing else refers to them.
+                heap.sweeper.set_drop();
+                Err(issue)
+            }
+        }
+    }
+
+    // Takes a slot out of the free list without placing a value in it.
+    // The slot is Lent until it is given a value, or handed back with unlend().
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    fn lend_index(&self) -> Result<usize, AllocError<()>> {
+        // SAFETY: Stable growth never moves any of the existing nodes.
+        let (index, ()) = unsafe { self.claim((), Growth::Stable) }?;
+        self.set_tag(index, self.tag(index).with_state(State::Lent));
+
+        self.update_stats(|stats| {
+            stats.lent += 1;
+            stats.allocations += 1;
+        });
+
+        self.debug_check();
+        Ok(index)
+    }
+
+    // Returns a Lent slot to the free list.
+    fn unlend(&self, index: usize) {
+        if self.tag(index).state() != State::Lent {
+            panic!("double free! [corrupted memory]");
+        }
+
+        self.update_stats(|stats| stats.lent -= 1);
+        self.free(index);
+    }
+
+    // Lends the memory of a slot as a raw block, see allocator.rs.
+    #[cfg(feature = "allocator-api2")]
+    pub(crate) fn lend(&self) -> Option<NonNull<T>> {
+        let index = self.lend_index().ok()?;
+        NonNull::new(self.value(index))
+    }
+
+    #[cfg(feature = "allocator-api2")]
+    pub(crate) fn reclaim(&self, value: NonNull<T>) {
+        // The value sits at the start of its slot, so it points to the slot as well.
+        let index = self
+            .memory()
+            .index_of(value.as_ptr().cast())
+            .expect("pointer does not belong to the heap! [corrupted memory]");
+
+        self.unlend(index);
+    }
+
+    // Shared handles keep their reference counts in a column next to the slots, see rc.rs.
+    // The column only grows once a shared handle is created, so other heaps never pay for it.
+    pub(crate) fn counts(&self, index: usize) -> Counts {
+        let counts = unsafe { &*self.counts.get() };
+        counts.get(index).copied().unwrap_or_default()
+    }
+
+    pub(crate) fn set_counts(&self, index: usize, value: Counts) {
+        let counts = unsafe { &mut *self.counts.get() };
+
+        if counts.len() <= index {
+            counts.resize(index + 1, Counts::default());
         }
+
+        counts[index] = value;
     }
 
     // internally used to make life easy
-    fn memory(&self) -> &mut Vec<DHeapNode<T>> {
-        unsafe { &mut *self.buffer.get() }
+    #[allow(clippy::mut_from_ref)]
+    fn memory(&self) -> &mut ChunkedVec<Slot<T, I>, Tag> {
+        unsafe { &mut *self.buffer.as_ptr() }
+    }
+
+    fn tag(&self, index: usize) -> Tag {
+        unsafe { *self.memory().get(index).1 }
+    }
+
+    fn set_tag(&self, index: usize, tag: Tag) {
+        unsafe { *self.memory().get(index).1 = tag }
+    }
+
+    // Raw pointers into a slot, which avoid creating references to its neighbours.
+    fn value(&self, index: usize) -> *mut T {
+        unsafe { Slot::value_ptr(self.memory().get(index).0) }
+    }
+
+    fn next(&self, index: usize) -> *mut I {
+        unsafe { Slot::next_ptr(self.memory().get(index).0) }
+    }
+
+    // Every index up to the Edge fits in I, which alloc() checks as the heap grows.
+    fn link(index: usize) -> I {
+        I::from_usize(index).expect("index out of range! [corrupted memory]")
+    }
+
+    // Links the slot at `index` into the free list, and retires its generation.
+    fn free(&self, index: usize) {
+        unsafe { *self.next(index) = Self::link(self.head.replace(index)) };
+        self.set_tag(index, self.tag(index).retire().with_state(State::Empty));
+
+        self.update_stats(|stats| {
+            stats.free += 1;
+            stats.frees += 1;
+        });
+
+        self.debug_check();
+    }
+
+    fn update_stats(&self, f: impl FnOnce(&mut DHeapStats)) {
+        let mut stats = self.stats.get();
+        f(&mut stats);
+        self.stats.set(stats);
+    }
+
+    /// Returns a snapshot of the heap's bookkeeping.
+    ///
+    /// This includes the number of live values, the length of the free list,
+    /// the peak usage, and the total number of allocations and frees.
+    pub fn stats(&self) -> DHeapStats {
+        DHeapStats {
+            capacity: self.capacity(),
+            ..self.stats.get()
+        }
+    }
+
+    /// Allocates memory for the given value `v` in the `DHeap` and returns a `DBox` pointing to it.
+    ///
+    /// Unlike `unsafe_new()`, this function never resizes the memory that is already in use. When the
+    /// reserved memory is exhausted, the heap continues in a freshly allocated chunk, so every existing
+    /// reference into the heap stays valid. A heap created with `with_capacity()` switches to chunks of
+    /// its current size the first time this happens.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the heap is poisoned, see `is_poisoned()`, which also happens when its free list is found
+    /// to be corrupted, or if the index type `I` cannot address any more slots.
+    #[allow(clippy::new_ret_no_self)]
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn new(&self, v: T) -> DBox<'_, T, I> {
+        // SAFETY: Stable growth never moves any of the existing nodes.
+        unsafe { self.expect_alloc(v, Growth::Stable) }
     }
 
     /// Allocates memory for the given value `v` in the `DHeap` and returns a `DBox` pointing to it.
@@ -106,40 +461,122 @@ impl<T> DHeap<T> {
     /// new element requires the vector to grow, any existing references to elements within the dense heap
     /// might become invalid. This risk should be carefully considered when using this heap.
     ///
-    /// One approach to mitigate this risk is to use safe_new().
+    /// One approach to mitigate this risk is to use safe_new() or new().
     ///
     /// # Safety
     ///
     /// Users must ensure that no references to elements within the dense heap are held when calling this function.
     /// If references are held, they may become invalid after the function call.
-    pub unsafe fn unsafe_new(&self, v: T) -> DBox<T> {
+    ///
+    /// # Panics
+    ///
+    /// Panics in the same cases as `new()`.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub unsafe fn unsafe_new(&self, v: T) -> DBox<'_, T, I> {
+        self.expect_alloc(v, Growth::InPlace)
+    }
+
+    // SAFETY: Same contract as alloc().
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    unsafe fn expect_alloc(&self, v: T, growth: Growth) -> DBox<'_, T, I> {
+        match self.alloc(v, growth) {
+            Ok(index) => DBox {
+                heap: self,
+                index: Self::link(index),
+                _marker: PhantomData,
+            },
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    // Places `v` into the slot pointed to by the head, and returns its index.
+    //
+    // SAFETY: With Growth::InPlace, the vector may be resized. The caller must uphold
+    // the contract of unsafe_new(). The other kinds of growth are always safe.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    unsafe fn alloc(&self, v: T, growth: Growth) -> Result<usize, AllocError<T>> {
+        let (index, v) = self.claim(v, growth)?;
+
+        // The slot has to be looked up again, as the push may have moved it.
+        self.value(index).write(v);
+        self.set_tag(index, self.tag(index).with_state(State::Holding));
+
+        self.update_stats(|stats| {
+            stats.live += 1;
+            stats.allocations += 1;
+            stats.peak = stats.peak.max(stats.live);
+        });
+
+        self.debug_check();
+        Ok(index)
+    }
+
+    // Takes the slot pointed to by the head out of the free list, and returns its index.
+    // The caller is responsible for giving the slot its new state. `v` is only passed
+    // through, so that it can be handed back inside of the error.
+    //
+    // SAFETY: Same contract as alloc().
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    unsafe fn claim<V>(&self, v: V, growth: Growth) -> Result<(usize, V), AllocError<V>> {
+        if self.is_poisoned() {
+            return Err(AllocError::Poisoned(v));
+        }
+
         let index = self.head.get();
+        let tag = self.tag(index);
+
+        match tag.state() {
+            State::Edge => {
+                // The new Edge may end up in the free list, so its index has to fit in I as well.
+                let next = match index
+                    .checked_add(1)
+                    .filter(|&next| I::from_usize(next).is_some())
+                {
+                    Some(next) => next,
+                    None => return Err(AllocError::IndexExhausted(v)),
+                };
+
+                if growth == Growth::Never && self.memory().is_full() {
+                    return Err(AllocError::CapacityExhausted(v));
+                }
+
+                self.head.set(next);
 
-        match self.memory()[index] {
-            Edge() => {
                 // The implementation's weak point lies in this push operation, which is unavoidable.
                 // When the end of the free block list is reached, a new element must be pushed
                 // during allocation. If the new element causes the vector to grow, it leads to a problem:
                 // any references to elements within the dense heap become invalid.
                 // It's crucial to carefully consider this risk when using this heap.
-                self.head.set(self.size());
-                self.memory().push(Edge());
+                // A new Edge takes over the generation of the previous one, so that slots
+                // which were released by trim() never come back with an older generation.
+                let edge = Tag::new(State::Edge, tag.generation());
+
+                let slot = Slot::link(Self::link(0));
+
+                match growth {
+                    Growth::InPlace => self.memory().push(slot, edge),
+                    Growth::Stable | Growth::Never => self.memory().push_stable(slot, edge),
+                }
+            }
+
+            State::Empty => {
+                self.head.set((*self.next(index)).to_usize());
+                self.update_stats(|stats| stats.free -= 1);
             }
 
-            Empty(next) => self.head.set(next),
-            _ => panic!("invalid head pointer! [corrupted memory]"),
+            _ => {
+                self.poison();
+                return Err(AllocError::Poisoned(v));
+            }
         }
 
-        self.memory()[index] = Holding(ManuallyDrop::new(v));
+        #[cfg(feature = "track-allocations")]
+        self.sweeper.record(index, Location::caller());
 
-        DBox {
-            heap: self,
-            index,
-            _marker: PhantomData,
-        }
+        Ok((index, v))
     }
 
-    /// Provides a safe alternative to `DHeap::new()` by attempting to allocate
+    /// Provides a safe alternative to `DHeap::unsafe_new()` by attempting to allocate
     /// memory without resizing the underlying vector.
     ///
     /// This function ensures that no existing references will be invalidated during
@@ -147,121 +584,1173 @@ impl<T> DHeap<T> {
     /// capacity within the reserved memory. However, if the reserved memory is
     /// exhausted, an error is returned.
     ///
+    /// Since it never grows the heap, it never calls into the global allocator either.
+    ///
     /// # Returns
     ///
     /// - `Ok(DBox<T>)` if the allocation was successful.
-    /// - `Err(&'static str)` if there is no available capacity within the reserved memory.
-    pub fn safe_new(&self, v: T) -> Result<DBox<T>, &'static str> {
-        if self.memory().len() == self.memory().capacity() {
-            Err("out of reserved memory!")
-        } else {
-            // SAFETY: The vector is not resized, so no existing references are invalidated.
-            unsafe { Ok(self.unsafe_new(v)) }
+    /// - `Err(AllocError::CapacityExhausted(v))` if there is no available capacity within the reserved memory.
+    /// - `Err(AllocError::IndexExhausted(v))` if the index type `I` cannot address another element.
+    /// - `Err(AllocError::Poisoned(v))` if the free list of the heap is corrupted.
+    ///
+    /// The rejected value is always handed back inside of the error.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn safe_new(&self, v: T) -> Result<DBox<'_, T, I>, AllocError<T>> {
+        // SAFETY: The vector is not resized, so no existing references are invalidated.
+        let index = unsafe { self.alloc(v, Growth::Never)? };
+
+        Ok(DBox {
+            heap: self,
+            index: Self::link(index),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Allocates every value produced by `iter`, and returns their `DBox`es in the same order.
+    ///
+    /// The batch first fills the slots in the free list, and the remaining values are appended
+    /// after the `Edge`. Room for the whole batch is reserved once up front, based on the lower
+    /// bound of the iterator's `size_hint()`. Like `new()`, this never moves memory that is already
+    /// in use, so every existing reference into the heap stays valid.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the heap is poisoned, see `is_poisoned()`, which also happens when its free list is found
+    /// to be corrupted, or if the index type `I` cannot address the batch.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn alloc_many(&self, iter: impl IntoIterator<Item = T>) -> Vec<DBox<'_, T, I>> {
+        let iter = iter.into_iter();
+        let (len, _) = iter.size_hint();
+
+        // The free slots are reused before anything is appended, so only the rest has to be reserved.
+        self.memory()
+            .reserve_stable(len.saturating_sub(self.stats.get().free));
+
+        let mut boxes = Vec::with_capacity(len);
+
+        for v in iter {
+            // SAFETY: Stable growth never moves any of the existing nodes.
+            boxes.push(unsafe { self.expect_alloc(v, Growth::Stable) });
         }
+
+        boxes
     }
 
-    /// Retrieves the current memory usage of the `DHeap`.
+    /// Allocates values produced by `iter` into `boxes` without growing the heap.
     ///
-    /// This function returns the number of elements in the underlying vector,
-    /// which represents the total memory occupied by the `DHeap`.
+    /// This is the bulk version of `safe_new()`. It fills the free list and the reserved memory
+    /// until either `iter` runs out, or the first value that does not fit is handed back inside
+    /// of the error. The boxes allocated before the failure stay in `boxes`, and the rest of the
+    /// iterator is left untouched, so passing `iter.by_ref()` allows the batch to be resumed.
     ///
     /// # Returns
     ///
-    /// - A `usize` representing the memory usage of the `DHeap`.
-    pub fn size(&self) -> usize {
-        self.memory().len()
-    }
-}
+    /// - `Ok(())` if every value was allocated.
+    /// - `Err(error)` with the same variants as `safe_new()` for the first value that could not be allocated.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn try_extend<'a>(
+        &'a self,
+        boxes: &mut Vec<DBox<'a, T, I>>,
+        iter: impl IntoIterator<Item = T>,
+    ) -> Result<(), AllocError<T>> {
+        let iter = iter.into_iter();
+        boxes.reserve(iter.size_hint().0);
 
-/// DBox is a smart pointer designed to work with the DHeap allocator.
-///
-/// It provides similar functionality to Box in the Rust standard library but is specifically tailored
-/// for use with the dense heap implementation (DHeap).
-pub struct DBox<'a, T> {
-    heap: &'a DHeap<T>,
-    index: usize,
-    _marker: PhantomData<T>,
-}
+        for v in iter {
+            boxes.push(self.safe_new(v)?);
+        }
 
-impl<'a, T> DBox<'a, T> {
-    fn data(&self) -> &'a DHeapNode<T> {
-        &self.heap.memory()[self.index]
+        Ok(())
     }
 
-    fn mut_data(&mut self) -> &'a mut DHeapNode<T> {
-        &mut self.heap.memory()[self.index]
+    /// Allocates a slot in the `DHeap` without initializing it, and returns a `DBoxUninit` pointing to it.
+    ///
+    /// The value can then be built directly inside of the heap, and the box turned into a `DBox`
+    /// with `DBoxUninit::write()` or `DBoxUninit::assume_init()`. Dropping the `DBoxUninit`
+    /// returns the slot to the heap without dropping anything. Like `new()`, the heap grows
+    /// in chunks if it has to.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the heap is poisoned, see `is_poisoned()`, which also happens when its free list is found
+    /// to be corrupted, or if the index type `I` cannot address any more slots.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn alloc_uninit(&self) -> DBoxUninit<'_, T, I> {
+        match self.lend_index() {
+            Ok(index) => DBoxUninit {
+                heap: self,
+                index: Self::link(index),
+            },
+            Err(error) => panic!("{}", error),
+        }
     }
 
-    /// Consumes the `DBox` and retrieves the inner value `T`.
+    /// Allocates a slot in the `DHeap` and fills it with the value returned by `f`.
     ///
-    /// This function replaces the `DBox`'s memory cell with a `Moved` state, indicating
-    /// that the memory has been moved out of the `DHeap` before the `DBox` is dropped.
-    /// After replacing the cell, it returns the inner value of the `DBox`.
+    /// The slot is taken before `f` is called, and its result is written straight into it,
+    /// which lets the compiler build large values in place instead of on the stack.
+    /// If `f` panics, the slot is returned to the heap.
     ///
-    /// # Returns
+    /// # Panics
     ///
-    /// - The inner value `T` contained within the `DBox`.
-    pub fn into_inner(mut self) -> T {
-        match replace(self.mut_data(), Moved()) {
-            Holding(value) => ManuallyDrop::into_inner(value),
-            _ => panic!("use after free! [corrupted memory]"),
+    /// Panics in the same cases as `new()`.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn alloc_with(&self, f: impl FnOnce() -> T) -> DBox<'_, T, I> {
+        let mut uninit = self.alloc_uninit();
+
+        // SAFETY: The slot was just initialized.
+        unsafe {
+            uninit.as_mut_ptr().write(f());
+            uninit.assume_init()
         }
     }
-}
 
-impl<'a, T> Drop for DBox<'a, T> {
-    fn drop(&mut self) {
-        match self.mut_data() {
-            Holding(value) => {
-                // SAFETY: The memory cell is immediately replaced with an empty cell after dropping.
-                unsafe { ManuallyDrop::drop(value) }
-            }
-            Moved() => {}
-            _ => panic!("double free! [corrupted memory]"),
-        }
+    /// Allocates a slot in the `DHeap` and fills it with the value returned by `f`, unless it fails.
+    ///
+    /// This is the fallible version of `alloc_with()`. If `f` returns an error,
+    /// the slot goes back to the free list and the error is handed back.
+    ///
+    /// # Panics
+    ///
+    /// Panics in the same cases as `new()`.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn try_alloc_with<E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<DBox<'_, T, I>, E> {
+        let uninit = self.alloc_uninit();
+        Ok(uninit.write(f()?))
+    }
 
-        *self.mut_data() = Empty(self.heap.head.replace(self.index));
+    /// Returns the number of slots the heap can hold without allocating more memory.
+    ///
+    /// This counts the slots that are in use as well, but not the `Edge`.
+    pub fn capacity(&self) -> usize {
+        // The Edge takes up one slot of the reserved memory.
+        self.memory().capacity() - 1
     }
-}
 
-impl<'a, T> Deref for DBox<'a, T> {
-    type Target = T;
+    /// Returns how many more values `safe_new()` can allocate before the reserved memory runs out.
+    ///
+    /// These are the slots in the free list, plus the reserved memory after the `Edge`.
+    pub fn remaining(&self) -> usize {
+        let memory = self.memory();
+        self.stats.get().free + memory.capacity() - memory.len()
+    }
 
-    fn deref(&self) -> &Self::Target {
-        if let Holding(value) = self.data() {
-            value.deref()
-        } else {
-            // SAFETY:
-            // This code is frequently executed, so we use unsafe code to bypass the match.
-            // This should never be reached unless memory corruption occurs, but the
-            // compiler isn't aware of this guarantee.
-            unsafe { unreachable_unchecked() }
-        }
+    /// Reserves memory for at least `additional` more values, so that `safe_new()` can allocate them.
+    ///
+    /// Since this takes `&mut self`, no `DBox` can be alive while the heap grows, and the memory is
+    /// free to move. A heap created with `with_capacity()` stays a single contiguous vector. Slots in
+    /// the free list count towards `additional`, so nothing is allocated if there are enough of them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the new capacity overflows `usize`.
+    pub fn reserve(&mut self, additional: usize) {
+        // The memory is reserved past the Edge, which the free slots do not need.
+        self.memory()
+            .reserve(additional.saturating_sub(self.stats.get().free));
     }
-}
 
-impl<'a, T> DerefMut for DBox<'a, T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        if let Holding(value) = self.mut_data() {
-            value.deref_mut()
-        } else {
-            // SAFETY:
-            // This code is frequently executed, so we use unsafe code to bypass the match.
-            // This should never be reached unless memory corruption occurs, but the
-            // compiler isn't aware of this guarantee.
-            unsafe { unreachable_unchecked() }
-        }
+    /// Tries to reserve memory for at least `additional` more values, so that `safe_new()` can allocate them.
+    ///
+    /// This is the fallible version of `reserve()`. If the memory cannot be allocated,
+    /// the error is returned instead of aborting, and the values in the heap are left untouched.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.memory()
+            .try_reserve(additional.saturating_sub(self.stats.get().free))
     }
-}
 
-impl<'a, T> AsRef<T> for DBox<'a, T> {
-    fn as_ref(&self) -> &T {
-        self.deref()
+    /// Retrieves the current memory usage of the `DHeap`.
+    ///
+    /// This function returns the number of elements in the underlying vector,
+    /// which represents the total memory occupied by the `DHeap`.
+    ///
+    /// # Returns
+    ///
+    /// - A `usize` representing the memory usage of the `DHeap`.
+    pub fn size(&self) -> usize {
+        self.memory().len()
     }
-}
 
-impl<'a, T> AsMut<T> for DBox<'a, T> {
-    fn as_mut(&mut self) -> &mut T {
-        self.deref_mut()
+    /// Returns the number of elements in each chunk of memory,
+    /// or `None` if the heap is still a single contiguous vector.
+    pub fn chunk_size(&self) -> Option<usize> {
+        self.memory().chunk_size()
+    }
+
+    /// Stores `v` in the heap and returns a `DKey` that refers to it.
+    ///
+    /// The value is owned by the heap rather than by a `DBox`, and stays in place until it is
+    /// taken out with `remove()`. Like `new()`, this never moves memory that is already in use,
+    /// so references returned by `get()` remain valid.
+    ///
+    /// Values that are still stored when the heap is dropped are handled by its `LeakPolicy`.
+    /// By default they are leaked, which means that their destructors never run. Remove them
+    /// before dropping the heap with `remove()` or `clear()`, or call `set_drop_on_leak()` to have
+    /// the heap drop them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the heap is poisoned, see `is_poisoned()`, which also happens when its free list is found
+    /// to be corrupted, or if the index type `I` cannot address any more slots.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn insert(&self, v: T) -> DKey<I> {
+        self.new(v).into_key()
+    }
+
+    /// Returns a reference to the value behind `key`,
+    /// or `None` if the value has been removed.
+    ///
+    /// Only values owned by the heap are found. A key never resolves to a value
+    /// that is owned by a `DBox` or another handle, even if its index and generation match.
+    pub fn get(&self, key: DKey<I>) -> Option<&T> {
+        self.resolve(key, State::Keyed)
+            .map(|value| unsafe { &*value })
+    }
+
+    // Returns a pointer to the value behind `key`, if it is still there and its slot is in `state`.
+    pub(crate) fn resolve(&self, key: DKey<I>, state: State) -> Option<*mut T> {
+        let index = key.index();
+
+        if index >= self.size() {
+            return None;
+        }
+
+        let tag = self.tag(index);
+
+        if tag.generation() == key.generation && tag.state() == state {
+            Some(self.value(index))
+        } else {
+            None
+        }
+    }
+
+    /// Returns a mutable reference to the value behind `key`,
+    /// or `None` if the value has been removed.
+    pub fn get_mut(&mut self, key: DKey<I>) -> Option<&mut T> {
+        self.resolve(key, State::Keyed)
+            .map(|value| unsafe { &mut *value })
+    }
+
+    /// Returns true if `key` still refers to a value owned by the heap.
+    pub fn contains_key(&self, key: DKey<I>) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Removes the value behind `key` from the heap and returns it,
+    /// or `None` if the value has already been removed.
+    ///
+    /// The slot is returned to the free list, and every copy of `key` stops resolving.
+    pub fn remove(&mut self, key: DKey<I>) -> Option<T> {
+        // SAFETY: The heap is borrowed mutably, so there are no references to the value.
+        unsafe { self.take(key, State::Keyed) }
+    }
+
+    // Moves the value behind `key` out of the heap, and frees its slot.
+    // Handles take their values with State::Holding, and keys with State::Keyed.
+    //
+    // SAFETY: There must not be any references to the value.
+    pub(crate) unsafe fn take(&self, key: DKey<I>, state: State) -> Option<T> {
+        let value = self.resolve(key, state)?.read();
+
+        self.update_stats(|stats| stats.live -= 1);
+        self.free(key.index());
+        Some(value)
+    }
+
+    /// Drops every value that is still stored in the heap, and returns their slots to the free list.
+    ///
+    /// The heap is borrowed mutably, so no handle can be alive: this drops the values stored with
+    /// `insert()` along with those of boxes that were forgotten. Unlike `set_drop_on_leak()`, this
+    /// works for values that borrow something. Leaked values are left alone, and the memory of
+    /// the heap is kept for future allocations.
+    pub fn clear(&mut self) {
+        for index in 0..self.size() {
+            let tag = self.tag(index);
+
+            if tag.state().has_value() {
+                let key = DKey {
+                    index: Self::link(index),
+                    generation: tag.generation(),
+                };
+
+                // SAFETY: The heap is borrowed mutably, so there are no references to the value.
+                // The slot is freed before the value is dropped, in case its destructor panics.
+                drop(unsafe { self.take(key, tag.state()) });
+            }
+        }
+    }
+
+    /// Returns an iterator over the values stored in the heap.
+    ///
+    /// Values are visited in the order they are laid out in memory, skipping free slots.
+    /// This takes `&mut self` so that no `DBox` can hand out a mutable reference to one of the
+    /// values while the iterator is alive; values owned through a `DKey` are always visited.
+    pub fn iter(&mut self) -> Iter<'_, T, I> {
+        Iter(self.entries())
+    }
+
+    /// Returns an iterator over mutable references to the values stored in the heap.
+    ///
+    /// Values are visited in the order they are laid out in memory, skipping free slots.
+    pub fn iter_mut(&mut self) -> IterMut<'_, T, I> {
+        IterMut(self.entries_mut())
+    }
+
+    /// Returns an iterator over the values stored in the heap, along with the `DKey` of each one.
+    ///
+    /// The keys of values stored with `insert()` can be passed to `get()`, `get_mut()` and `remove()`
+    /// once the iterator is gone. The other keys belong to boxes that were forgotten, and never resolve.
+    pub fn entries(&mut self) -> Entries<'_, T, I> {
+        Entries {
+            heap: self,
+            index: 0,
+        }
+    }
+
+    /// Returns an iterator over mutable references to the values stored in the heap,
+    /// along with the `DKey` of each one.
+    pub fn entries_mut(&mut self) -> EntriesMut<'_, T, I> {
+        EntriesMut {
+            heap: self,
+            index: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Moves the values at the end of the heap into the free slots before them,
+    /// then releases the memory that is no longer needed.
+    ///
+    /// Returns the number of slots that were released.
+    ///
+    /// This is equivalent to `compact_with()` with a callback that does nothing. Any `DKey`
+    /// pointing to a value that was moved stops resolving, so use `compact_with()` to keep track
+    /// of relocated values.
+    pub fn compact(&mut self) -> usize {
+        self.compact_with(|_, _| {})
+    }
+
+    /// Moves the values at the end of the heap into the free slots before them,
+    /// then releases the memory that is no longer needed with `trim()`.
+    ///
+    /// Every time a value is moved, `relocate` is called with the key it used to have and the key
+    /// it has now, so that keys stored elsewhere can be updated. Afterwards the values occupy the
+    /// front of the heap, the free list is rebuilt from the remaining holes, and the buffer is shrunk.
+    /// A flat heap is shrunk down to its exact size, but never below the capacity it was created with,
+    /// while a chunked heap releases whole chunks.
+    ///
+    /// This takes `&mut self`, so the borrow checker guarantees that no `DBox` is alive while
+    /// values are being moved. Only values owned through a `DKey` can ever be relocated.
+    /// If `relocate` panics, the values that were already moved stay where they are, and the heap
+    /// is trimmed while unwinding, so it can still be used afterwards.
+    ///
+    /// Returns the number of slots that were released.
+    pub fn compact_with(&mut self, mut relocate: impl FnMut(DKey<I>, DKey<I>)) -> usize {
+        let old_size = self.size();
+
+        // The free list stays broken until trim() rebuilds it, which the guard
+        // also takes care of while unwinding if `relocate` panics.
+        let guard = Compaction { heap: self };
+        let heap = &mut *guard.heap;
+
+        let is_empty = |heap: &Self, index| heap.tag(index).state() == State::Empty;
+        let is_holding = |heap: &Self, index| heap.tag(index).state().has_value();
+
+        // Fill the lowest holes with the highest values, until the two meet.
+        let mut hole = 0;
+        let mut tail = old_size - 1;
+
+        loop {
+            while hole < tail && !is_empty(heap, hole) {
+                hole += 1;
+            }
+
+            while tail > hole && !is_holding(heap, tail - 1) {
+                tail -= 1;
+            }
+
+            if hole >= tail {
+                break;
+            }
+
+            let from = tail - 1;
+            unsafe { ptr::copy_nonoverlapping(heap.value(from), heap.value(hole), 1) };
+
+            let (from_tag, hole_tag) = (heap.tag(from), heap.tag(hole));
+
+            // Retire the old slot, so that the old key stops resolving.
+            // Its link is rewritten by trim() afterwards.
+            heap.set_tag(from, from_tag.retire().with_state(State::Empty));
+            heap.set_tag(hole, hole_tag.with_state(from_tag.state()));
+
+            let old_key = DKey {
+                index: Self::link(from),
+                generation: from_tag.generation(),
+            };
+
+            let new_key = DKey {
+                index: Self::link(hole),
+                generation: hole_tag.generation(),
+            };
+
+            #[cfg(feature = "track-allocations")]
+            heap.sweeper.relocate(from, hole);
+
+            relocate(old_key, new_key);
+        }
+
+        drop(guard);
+        old_size - self.size()
+    }
+
+    /// Releases the free slots at the end of the heap, right before the `Edge`.
+    ///
+    /// The free list is rebuilt from the holes that remain, lowest index first, and the buffer is
+    /// shrunk. A flat heap is shrunk down to its exact size, but never below the capacity it was
+    /// created with, while a chunked heap releases whole chunks. Unlike `compact()`, no values are
+    /// moved, so every `DKey` keeps resolving.
+    ///
+    /// Returns the number of slots that were released.
+    pub fn trim(&mut self) -> usize {
+        let old_size = self.size();
+
+        // Everything after the last occupied slot can be released.
+        let mut end = old_size - 1;
+        while end > 0 && self.tag(end - 1).state() == State::Empty {
+            end -= 1;
+        }
+
+        let generation = (end..old_size)
+            .map(|i| self.tag(i).generation())
+            .max()
+            .unwrap_or(0);
+
+        self.memory().truncate(end);
+        self.memory()
+            .push(Slot::link(Self::link(0)), Tag::new(State::Edge, generation));
+        self.memory().shrink_to_fit();
+
+        // The reference counts of the released slots go with them.
+        let counts = self.counts.get_mut();
+        counts.truncate(end);
+        counts.shrink_to_fit();
+
+        // Chain the remaining holes back together, lowest index first.
+        let mut head = end;
+        let mut free = 0;
+
+        for index in (0..end).rev() {
+            if self.tag(index).state() == State::Empty {
+                unsafe { *self.next(index) = Self::link(head) };
+                head = index;
+                free += 1;
+            }
+        }
+
+        self.head.set(head);
+        self.update_stats(|stats| stats.free = free);
+        self.debug_check();
+
+        old_size - self.size()
+    }
+
+    /// Checks the integrity of the heap, and returns a report of everything that is wrong with it.
+    ///
+    /// This walks the free list from the head, and checks that every link points at a free slot
+    /// without ever coming back around, that every freed slot can be reached, that the `Edge` is
+    /// the last slot and only the last slot, and that the counters of `stats()` match the slots.
+    /// The values themselves are never touched, so this is safe to call while `DBox`es are alive.
+    ///
+    /// With the `debug-checks` feature, the heap validates itself after every operation
+    /// and panics as soon as the report is not ok.
+    pub fn validate(&self) -> DHeapReport {
+        let mut report = DHeapReport::default();
+        let size = self.size();
+
+        for index in 0..size {
+            match self.tag(index).state() {
+                State::Edge if index + 1 != size => {
+                    report.issues.push(DHeapIssue::MisplacedEdge { index })
+                }
+                State::Edge => {}
+                State::Empty => report.empty += 1,
+                State::Holding | State::Keyed => report.holding += 1,
+                State::Moved => report.moved += 1,
+                State::Lent => report.lent += 1,
+                State::Leaked => report.leaked += 1,
+            }
+        }
+
+        let state = self.tag(size - 1).state();
+        if state != State::Edge {
+            report.issues.push(DHeapIssue::MissingEdge { state });
+        }
+
+        // Walk the free list until it reaches the Edge, or breaks.
+        let mut linked = vec![false; size];
+        let (mut from, mut to) = (None, self.head.get());
+
+        loop {
+            if to >= size {
+                report.issues.push(DHeapIssue::LinkOutOfBounds { from, to });
+                break;
+            }
+
+            match self.tag(to).state() {
+                State::Edge => break,
+                State::Empty if linked[to] => {
+                    report.issues.push(DHeapIssue::Cycle { from, to });
+                    break;
+                }
+                State::Empty => {
+                    linked[to] = true;
+                    report.linked += 1;
+                    (from, to) = (Some(to), unsafe { (*self.next(to)).to_usize() });
+                }
+                state => {
+                    report
+                        .issues
+                        .push(DHeapIssue::LinkToUsedSlot { from, to, state });
+                    break;
+                }
+            }
+        }
+
+        for (index, &linked) in linked.iter().enumerate() {
+            if !linked && self.tag(index).state() == State::Empty {
+                report.issues.push(DHeapIssue::Unlinked { index });
+            }
+        }
+
+        let stats = self.stats.get();
+        let counted = (
+            stats.live,
+            stats.free,
+            stats.moved,
+            stats.lent,
+            stats.leaked,
+        );
+
+        let found = (
+            report.holding,
+            report.empty,
+            report.moved,
+            report.lent,
+            report.leaked,
+        );
+
+        if counted != found {
+            report.issues.push(DHeapIssue::StatsMismatch);
+        }
+
+        report
+    }
+
+    // Validates the heap after an operation, when the debug-checks feature is enabled.
+    fn debug_check(&self) {
+        #[cfg(feature = "debug-checks")]
+        {
+            let report = self.validate();
+
+            if let Some(issue) = report.issues.first() {
+                self.poison();
+                panic!("{} [corrupted memory]", issue);
+            }
+        }
+    }
+
+    // Overwrites the state of a slot, so that tests can break the heap on purpose.
+    #[cfg(all(test, any(debug_assertions, feature = "checked")))]
+    pub(crate) fn corrupt_state(&self, index: usize, state: State) {
+        self.set_tag(index, self.tag(index).with_state(state));
+    }
+
+    // Overwrites the link of a free slot, so that tests can break the free list on purpose.
+    #[cfg(test)]
+    pub(crate) fn corrupt_link(&self, index: usize, next: usize) {
+        unsafe { *self.next(index) = Self::link(next) };
+    }
+
+    /// Returns true if the heap has found its own metadata in an inconsistent state.
+    ///
+    /// Like a poisoned `Mutex`, a poisoned heap keeps working for the values that are already
+    /// in it, so boxes can still be dropped. It refuses to allocate anything new though:
+    /// `safe_new()` returns `AllocError::Poisoned`, and `new()` panics.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.get()
+    }
+
+    /// Clears the poisoned state of the heap, so that it allocates again.
+    ///
+    /// The heap is not repaired in any way. Only do this after checking it with `validate()`.
+    pub fn clear_poison(&mut self) {
+        self.poisoned.set(false);
+    }
+
+    /// Sets what happens to the values that are still in the heap when it is dropped, see `LeakPolicy`.
+    ///
+    /// Values are left behind when their `DBox` is forgotten, or when they were stored with `insert()`
+    /// and never removed. By default they are leaked silently. This undoes `set_drop_on_leak()`.
+    pub fn set_leak_policy(&mut self, policy: LeakPolicy) {
+        self.sweeper.set_policy(policy);
+    }
+
+    /// Makes the heap drop the values that are still in it when it is dropped, instead of applying
+    /// its `LeakPolicy`, until `set_leak_policy()` is called again.
+    ///
+    /// Since the heap may be dropped after anything its values borrow, this is only available
+    /// for values that do not borrow anything. Other heaps can drop their values with `clear()`.
+    pub fn set_drop_on_leak(&mut self)
+    where
+        T: 'static,
+    {
+        self.sweeper.set_drop();
+    }
+
+    pub(crate) fn poison(&self) {
+        self.poisoned.set(true);
+    }
+
+    // Finds the next Holding or Keyed slot at or after `*index`, and moves the cursor past it.
+    fn next_holding(&self, index: &mut usize) -> Option<(DKey<I>, *mut T)> {
+        while *index < self.size() {
+            let current = *index;
+            *index += 1;
+
+            let tag = self.tag(current);
+
+            if tag.state().has_value() {
+                let key = DKey {
+                    index: Self::link(current),
+                    generation: tag.generation(),
+                };
+
+                return Some((key, self.value(current)));
+            }
+        }
+
+        None
+    }
+}
+
+/// DBox is a smart pointer designed to work with the DHeap allocator.
+///
+/// It provides similar functionality to Box in the Rust standard library but is specifically tailored
+/// for use with the dense heap implementation (DHeap).
+pub struct DBox<'a, T, I: DIndex = usize> {
+    heap: &'a DHeap<T, I>,
+    index: I,
+    _marker: PhantomData<T>,
+}
+
+impl<'a, T, I: DIndex> DBox<'a, T, I> {
+    fn index(&self) -> usize {
+        self.index.to_usize()
+    }
+
+    fn state(&self) -> State {
+        self.heap.tag(self.index()).state()
+    }
+
+    /// Consumes the `DBox` and retrieves the inner value `T`.
+    ///
+    /// This function marks the `DBox`'s memory cell with a `Moved` state, indicating
+    /// that the memory has been moved out of the `DHeap` before the `DBox` is dropped.
+    /// After marking the cell, it returns the inner value of the `DBox`.
+    ///
+    /// # Returns
+    ///
+    /// - The inner value `T` contained within the `DBox`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value was already moved out, see `try_into_inner()`.
+    pub fn into_inner(self) -> T {
+        self.try_into_inner()
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Consumes the `DBox` and retrieves the inner value `T`, or reports that the heap is corrupted.
+    ///
+    /// This is the same as `into_inner()`, but it returns `FreeError::UseAfterFree` instead
+    /// of panicking if the slot of the box no longer holds its value. The heap is poisoned
+    /// in that case, and the slot is left alone.
+    pub fn try_into_inner(self) -> Result<T, FreeError> {
+        let tag = self.heap.tag(self.index());
+
+        if tag.state() != State::Holding {
+            self.heap.poison();
+            let index = self.index();
+            forget(self);
+            return Err(FreeError::UseAfterFree(index));
+        }
+
+        self.heap
+            .set_tag(self.index(), tag.with_state(State::Moved));
+        self.heap.update_stats(|stats| {
+            stats.live -= 1;
+            stats.moved += 1;
+        });
+        self.heap.debug_check();
+
+        Ok(unsafe { self.heap.value(self.index()).read() })
+    }
+
+    /// Drops the value of the `DBox` and returns its slot to the heap, or reports that the heap is corrupted.
+    ///
+    /// This is the same as dropping the box, but it returns `FreeError::DoubleFree` instead
+    /// of panicking if the slot was already freed. The heap is poisoned in that case,
+    /// and the slot is left alone.
+    pub fn try_free(self) -> Result<(), FreeError> {
+        let mut this = ManuallyDrop::new(self);
+        this.release()
+    }
+
+    // Drops the value, if it is still there, and returns the slot to the free list.
+    //
+    // The value is marked as moved out before it is dropped. If its destructor panics,
+    // the slot is still freed while unwinding, so the heap stays consistent.
+    fn release(&mut self) -> Result<(), FreeError> {
+        let tag = self.heap.tag(self.index());
+
+        let release = Release {
+            heap: self.heap,
+            index: self.index(),
+        };
+
+        match tag.state() {
+            State::Holding => {
+                self.heap
+                    .set_tag(self.index(), tag.with_state(State::Moved));
+                self.heap.update_stats(|stats| {
+                    stats.live -= 1;
+                    stats.moved += 1;
+                });
+
+                // SAFETY: The slot was holding the value, and is freed right after it is dropped.
+                unsafe { drop_in_place(self.heap.value(self.index())) }
+            }
+            State::Moved => {}
+            _ => {
+                forget(release);
+                self.heap.poison();
+                return Err(FreeError::DoubleFree(self.index()));
+            }
+        }
+
+        drop(release);
+        Ok(())
+    }
+
+    /// Replaces the value of the `DBox` with `v`, and returns the old value.
+    pub fn replace(&mut self, v: T) -> T {
+        mem::replace(self.deref_mut(), v)
+    }
+
+    /// Takes the value out of the `DBox`, leaving `T::default()` in its place.
+    pub fn take(&mut self) -> T
+    where
+        T: Default,
+    {
+        mem::take(self.deref_mut())
+    }
+
+    /// Swaps the slots owned by two boxes.
+    ///
+    /// Only the handles are exchanged, so the values stay where they are in their heaps.
+    /// This is the same as `mem::swap()` on the boxes, which is just as cheap.
+    pub fn swap(a: &mut Self, b: &mut Self) {
+        mem::swap(a, b)
+    }
+
+    /// Replaces the value of the `DBox` with the result of calling `f` on it.
+    ///
+    /// The value is marked as moved out while `f` runs. If `f` panics, the old value
+    /// has already been dropped by it, and the slot is freed along with the box.
+    pub fn map_in_place(&mut self, f: impl FnOnce(T) -> T) {
+        let index = self.index();
+        let value: *mut T = self.deref_mut();
+
+        self.heap
+            .set_tag(index, self.heap.tag(index).with_state(State::Moved));
+        self.heap.update_stats(|stats| {
+            stats.live -= 1;
+            stats.moved += 1;
+        });
+
+        // SAFETY: The slot was holding the value, and is marked as moved until it is written back.
+        let v = f(unsafe { value.read() });
+
+        // The slot has to be looked up again, as `f` may have resized the heap with unsafe_new().
+        unsafe { self.heap.value(index).write(v) };
+        self.heap
+            .set_tag(index, self.heap.tag(index).with_state(State::Holding));
+        self.heap.update_stats(|stats| {
+            stats.moved -= 1;
+            stats.live += 1;
+        });
+        self.heap.debug_check();
+    }
+
+    /// Consumes the `DBox` and returns a mutable reference to its value that lives as long as the heap.
+    ///
+    /// The slot is retired for good: it is never reused, and the value is never dropped,
+    /// not even by `set_drop_on_leak()`. It is counted as `leaked` in the statistics of the heap.
+    /// Snapshots still write the value out, and it stays leaked in the restored heap.
+    pub fn leak(self) -> &'a mut T {
+        let (heap, index) = (self.heap, self.index());
+        let value: *mut T = ManuallyDrop::new(self).deref_mut().deref_mut();
+
+        heap.set_tag(index, heap.tag(index).with_state(State::Leaked));
+        heap.update_stats(|stats| {
+            stats.live -= 1;
+            stats.leaked += 1;
+        });
+        heap.debug_check();
+
+        // SAFETY: The slot is never touched by the heap again, and its memory stays put
+        // for as long as the heap is borrowed, following the contract of unsafe_new().
+        unsafe { &mut *value }
+    }
+
+    // Hands the value over to another kind of handle, which frees it with DHeap::take().
+    // The pointer comes from the slot itself rather than from a shared reference, so the new
+    // handle may write through it.
+    pub(crate) fn into_raw(self) -> (DKey<I>, NonNull<T>) {
+        let mut this = ManuallyDrop::new(self);
+        let value = NonNull::from(&mut **this);
+
+        let key = DKey {
+            index: this.index,
+            generation: this.heap.tag(this.index()).generation(),
+        };
+
+        (key, value)
+    }
+
+    /// Consumes the `DBox` and hands ownership of its value over to the heap.
+    ///
+    /// The value stays where it is, and can be accessed and removed through the returned `DKey`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value was already moved out.
+    pub fn into_key(self) -> DKey<I> {
+        let (heap, index) = (self.heap, self.index());
+        let tag = heap.tag(index);
+
+        if tag.state() != State::Holding {
+            heap.poison();
+            forget(self);
+            panic!("use after free! [corrupted memory]");
+        }
+
+        forget(self);
+        heap.set_tag(index, tag.with_state(State::Keyed));
+        heap.debug_check();
+
+        DKey {
+            index: DHeap::<T, I>::link(index),
+            generation: tag.generation(),
+        }
+    }
+}
+
+impl<'a, T, I: DIndex> Drop for DBox<'a, T, I> {
+    fn drop(&mut self) {
+        if let Err(error) = self.release() {
+            panic!("{}", error);
+        }
+    }
+}
+
+/// DBoxUninit is a slot of a DHeap that was allocated without a value, see `DHeap::alloc_uninit()`.
+///
+/// It dereferences to a `MaybeUninit<T>`, which can be initialized in place. The slot is
+/// counted as `lent` in the statistics of the heap until the box is turned into a `DBox`.
+pub struct DBoxUninit<'a, T, I: DIndex = usize> {
+    heap: &'a DHeap<T, I>,
+    index: I,
+}
+
+impl<'a, T, I: DIndex> DBoxUninit<'a, T, I> {
+    fn index(&self) -> usize {
+        self.index.to_usize()
+    }
+
+    /// Writes `v` into the slot and returns a `DBox` that owns it.
+    pub fn write(mut self, v: T) -> DBox<'a, T, I> {
+        // SAFETY: The slot was just initialized.
+        unsafe {
+            self.as_mut_ptr().write(v);
+            self.assume_init()
+        }
+    }
+
+    /// Turns the box into a `DBox` that owns the value in the slot.
+    ///
+    /// # Safety
+    ///
+    /// The value must have been fully initialized, as with `MaybeUninit::assume_init()`.
+    pub unsafe fn assume_init(self) -> DBox<'a, T, I> {
+        let (heap, index) = (self.heap, self.index);
+        forget(self);
+
+        let tag = heap.tag(index.to_usize());
+
+        if tag.state() != State::Lent {
+            heap.poison();
+            panic!("use after free! [corrupted memory]");
+        }
+
+        heap.set_tag(index.to_usize(), tag.with_state(State::Holding));
+        heap.update_stats(|stats| {
+            stats.lent -= 1;
+            stats.live += 1;
+            stats.peak = stats.peak.max(stats.live);
+        });
+        heap.debug_check();
+
+        DBox {
+            heap,
+            index,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, T, I: DIndex> Drop for DBoxUninit<'a, T, I> {
+    fn drop(&mut self) {
+        self.heap.unlend(self.index());
+    }
+}
+
+impl<'a, T, I: DIndex> Deref for DBoxUninit<'a, T, I> {
+    type Target = MaybeUninit<T>;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: MaybeUninit<T> has the same layout as T, and any contents are valid for it.
+        unsafe { &*self.heap.value(self.index()).cast() }
+    }
+}
+
+impl<'a, T, I: DIndex> DerefMut for DBoxUninit<'a, T, I> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: Same reasoning as in DBoxUninit::deref().
+        unsafe { &mut *self.heap.value(self.index()).cast() }
+    }
+}
+
+impl<'a, T, I: DIndex> fmt::Debug for DBoxUninit<'a, T, I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DBoxUninit")
+            .field("index", &self.index())
+            .finish_non_exhaustive()
+    }
+}
+
+// Release returns a slot whose value was moved out or dropped to the free list when it goes out of scope,
+// which also happens while unwinding from a panic in the destructor of the value.
+struct Release<'a, T, I: DIndex> {
+    heap: &'a DHeap<T, I>,
+    index: usize,
+}
+
+impl<'a, T, I: DIndex> Drop for Release<'a, T, I> {
+    fn drop(&mut self) {
+        self.heap.update_stats(|stats| stats.moved -= 1);
+        self.heap.free(self.index);
+    }
+}
+
+// Compaction rebuilds the free list of a heap with trim() when it goes out of scope,
+// so that a panic in a relocation callback does not leave compact_with() halfway done.
+struct Compaction<'a, T, I: DIndex> {
+    heap: &'a mut DHeap<T, I>,
+}
+
+impl<'a, T, I: DIndex> Drop for Compaction<'a, T, I> {
+    fn drop(&mut self) {
+        self.heap.trim();
+    }
+}
+
+impl<'a, T, I: DIndex> Deref for DBox<'a, T, I> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        let state = self.state();
+
+        if state == State::Holding {
+            unsafe { &*self.heap.value(self.index()) }
+        } else {
+            // SAFETY:
+            // This code is frequently executed, so we use unsafe code to bypass the match.
+            // This should never be reached unless memory corruption occurs, but the
+            // compiler isn't aware of this guarantee. Debug builds and the `checked`
+            // feature panic here instead.
+            unsafe { not_holding(self.index(), state) }
+        }
+    }
+}
+
+impl<'a, T, I: DIndex> DerefMut for DBox<'a, T, I> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        let state = self.state();
+
+        if state == State::Holding {
+            unsafe { &mut *self.heap.value(self.index()) }
+        } else {
+            // SAFETY: Same reasoning as in DBox::deref().
+            unsafe { not_holding(self.index(), state) }
+        }
+    }
+}
+
+impl<'a, T, I: DIndex> AsRef<T> for DBox<'a, T, I> {
+    fn as_ref(&self) -> &T {
+        self.deref()
+    }
+}
+
+impl<'a, T, I: DIndex> AsMut<T> for DBox<'a, T, I> {
+    fn as_mut(&mut self) -> &mut T {
+        self.deref_mut()
+    }
+}
+
+impl<'a, T, I: DIndex> Borrow<T> for DBox<'a, T, I> {
+    fn borrow(&self) -> &T {
+        self.deref()
+    }
+}
+
+impl<'a, T, I: DIndex> BorrowMut<T> for DBox<'a, T, I> {
+    fn borrow_mut(&mut self) -> &mut T {
+        self.deref_mut()
+    }
+}
+
+/// Cloning a DBox clones the value into a new slot of the same DHeap.
+///
+/// The new slot is allocated with `DHeap::new()`, so the heap grows if it has to.
+impl<'a, T: Clone, I: DIndex> Clone for DBox<'a, T, I> {
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    fn clone(&self) -> Self {
+        self.heap.new(self.deref().clone())
+    }
+}
+
+impl<'a, T: fmt::Debug, I: DIndex> fmt::Debug for DBox<'a, T, I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.deref(), f)
+    }
+}
+
+impl<'a, T: fmt::Display, I: DIndex> fmt::Display for DBox<'a, T, I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.deref(), f)
+    }
+}
+
+/// Formats the address of the value inside of the DHeap.
+impl<'a, T, I: DIndex> fmt::Pointer for DBox<'a, T, I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Pointer::fmt(&(self.deref() as *const T), f)
+    }
+}
+
+impl<'a, T: PartialEq, I: DIndex> PartialEq for DBox<'a, T, I> {
+    fn eq(&self, other: &Self) -> bool {
+        PartialEq::eq(self.deref(), other.deref())
+    }
+}
+
+impl<'a, T: Eq, I: DIndex> Eq for DBox<'a, T, I> {}
+
+impl<'a, T: PartialOrd, I: DIndex> PartialOrd for DBox<'a, T, I> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        PartialOrd::partial_cmp(self.deref(), other.deref())
+    }
+}
+
+impl<'a, T: Ord, I: DIndex> Ord for DBox<'a, T, I> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        Ord::cmp(self.deref(), other.deref())
+    }
+}
+
+impl<'a, T: Hash, I: DIndex> Hash for DBox<'a, T, I> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.deref().hash(state)
+    }
+}
+
+/// An iterator over the values of a DHeap, created by `DHeap::iter()`.
+pub struct Iter<'a, T, I: DIndex = usize>(Entries<'a, T, I>);
+
+impl<'a, T, I: DIndex> Iterator for Iter<'a, T, I> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(_, value)| value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+/// An iterator over mutable references to the values of a DHeap, created by `DHeap::iter_mut()`.
+pub struct IterMut<'a, T, I: DIndex = usize>(EntriesMut<'a, T, I>);
+
+impl<'a, T, I: DIndex> Iterator for IterMut<'a, T, I> {
+    type Item = &'a mut T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(_, value)| value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+/// An iterator over the keys and values of a DHeap, created by `DHeap::entries()`.
+pub struct Entries<'a, T, I: DIndex = usize> {
+    heap: &'a DHeap<T, I>,
+    index: usize,
+}
+
+impl<'a, T, I: DIndex> Iterator for Entries<'a, T, I> {
+    type Item = (DKey<I>, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = self.heap.next_holding(&mut self.index)?;
+        Some((key, unsafe { &*value }))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.heap.size() - self.index))
+    }
+}
+
+/// An iterator over the keys and mutable values of a DHeap, created by `DHeap::entries_mut()`.
+pub struct EntriesMut<'a, T, I: DIndex = usize> {
+    heap: &'a DHeap<T, I>,
+    index: usize,
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<'a, T, I: DIndex> Iterator for EntriesMut<'a, T, I> {
+    type Item = (DKey<I>, &'a mut T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // SAFETY: The heap is mutably borrowed for 'a, and every slot is visited at most once.
+        let (key, value) = self.heap.next_holding(&mut self.index)?;
+        Some((key, unsafe { &mut *value }))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.heap.size() - self.index))
     }
 }
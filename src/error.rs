@@ -0,0 +1,101 @@
+// error.rs --- allocation errors for the dense heap.
+
+// Copyright (c) 2023 Sam Belliveau. All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use core::fmt;
+
+/// AllocError describes why a value could not be placed in a DHeap.
+///
+/// Every variant hands back the value that was rejected, so the caller can retry
+/// the allocation elsewhere without having to clone it up front.
+pub enum AllocError<T> {
+    /// The reserved memory is used up, and growing the buffer
+    /// could invalidate references into the heap.
+    CapacityExhausted(T),
+
+    /// Every index the heap is able to address is already in use.
+    IndexExhausted(T),
+
+    /// The heap found its own metadata in an inconsistent state
+    /// and refuses to hand out any more memory.
+    Poisoned(T),
+}
+
+impl<T> AllocError<T> {
+    /// Consumes the error and returns the value that failed to be allocated.
+    pub fn into_inner(self) -> T {
+        match self {
+            AllocError::CapacityExhausted(v) => v,
+            AllocError::IndexExhausted(v) => v,
+            AllocError::Poisoned(v) => v,
+        }
+    }
+}
+
+// Debug is written by hand so that it does not require `T: Debug`,
+// which in turn lets `AllocError<T>` implement `Error` for every `T`.
+impl<T> fmt::Debug for AllocError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllocError::CapacityExhausted(_) => f.write_str("CapacityExhausted(..)"),
+            AllocError::IndexExhausted(_) => f.write_str("IndexExhausted(..)"),
+            AllocError::Poisoned(_) => f.write_str("Poisoned(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for AllocError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllocError::CapacityExhausted(_) => f.write_str("out of reserved memory!"),
+            AllocError::IndexExhausted(_) => f.write_str("out of addressable indices!"),
+            AllocError::Poisoned(_) => f.write_str("heap is poisoned! [corrupted memory]"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> std::error::Error for AllocError<T> {}
+
+/// FreeError describes why a DBox could not give its slot back to the DHeap.
+///
+/// Both variants mean that the heap's metadata no longer matches its boxes. The heap is
+/// poisoned when this happens, and every variant carries the index of the offending slot.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FreeError {
+    /// The value of the box was already moved out or dropped.
+    UseAfterFree(usize),
+
+    /// The slot of the box was already returned to the free list.
+    DoubleFree(usize),
+}
+
+impl fmt::Display for FreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FreeError::UseAfterFree(_) => f.write_str("use after free! [corrupted memory]"),
+            FreeError::DoubleFree(_) => f.write_str("double free! [corrupted memory]"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for FreeError {}
@@ -0,0 +1,60 @@
+// index.rs --- index types for addressing the slots of a dense heap.
+
+// Copyright (c) 2023 Sam Belliveau. All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use core::{fmt::Debug, hash::Hash};
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// DIndex is implemented by the integer types a DHeap can use to address its slots.
+///
+/// The index type is used for the free list links stored in every free slot, as well as for
+/// every DBox and DKey. A narrow index makes all of these smaller, but limits the number of
+/// slots the heap can address. Allocations past that limit fail with `AllocError::IndexExhausted`.
+///
+/// This trait is sealed, and implemented for `u8`, `u16`, `u32` and `usize`.
+pub trait DIndex: Copy + Eq + Ord + Hash + Debug + Send + Sync + 'static + private::Sealed {
+    /// Converts a slot index into this type, or returns `None` if it does not fit.
+    fn from_usize(index: usize) -> Option<Self>;
+
+    /// Converts this index back into a slot index.
+    fn to_usize(self) -> usize;
+}
+
+macro_rules! impl_dindex {
+    ($($t:ty),*) => {$(
+        impl private::Sealed for $t {}
+
+        impl DIndex for $t {
+            fn from_usize(index: usize) -> Option<Self> {
+                <$t>::try_from(index).ok()
+            }
+
+            fn to_usize(self) -> usize {
+                self as usize
+            }
+        }
+    )*};
+}
+
+impl_dindex!(u8, u16, u32, usize);
@@ -0,0 +1,206 @@
+// leak.rs --- leak detection for the dense heap.
+
+// Copyright (c) 2023 Sam Belliveau. All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::boxed::Box;
+use core::{fmt, panic::Location, ptr::NonNull};
+
+#[cfg(feature = "track-allocations")]
+use alloc::vec::Vec;
+#[cfg(feature = "track-allocations")]
+use core::cell::UnsafeCell;
+
+use crate::{
+    index::DIndex,
+    slot::{Slot, Tag},
+    storage::ChunkedVec,
+};
+
+/// LeakPolicy decides what happens to the values that are still in a DHeap when it is dropped.
+///
+/// Values are left behind when their DBox is forgotten with `mem::forget()`, or when they were
+/// stored with `DHeap::insert()` and never removed. The policy is set with `DHeap::set_leak_policy()`,
+/// for any type of value. Heaps of values that do not borrow anything can drop them instead,
+/// see `DHeap::set_drop_on_leak()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum LeakPolicy {
+    /// The values are left in place, and their destructors never run. This is the default.
+    #[default]
+    Leak,
+
+    /// The function is called once for every value that was left behind, which is then leaked.
+    Report(fn(Leak)),
+}
+
+/// A Leak describes a value that was left behind in a DHeap when it was dropped.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Leak {
+    /// The index of the slot holding the value.
+    pub index: usize,
+
+    /// The generation of the slot, which matches the `DKey` of the value.
+    pub generation: u32,
+
+    /// Where the value was allocated, if the `track-allocations` feature is enabled.
+    pub site: Option<&'static Location<'static>>,
+}
+
+impl fmt::Display for Leak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "slot {} was leaked", self.index)?;
+
+        match self.site {
+            Some(site) => write!(f, " (allocated at {})", site),
+            None => Ok(()),
+        }
+    }
+}
+
+// The storage of a DHeap.
+type Memory<T, I> = ChunkedVec<Slot<T, I>, Tag>;
+
+// The Sweeper owns the memory of a DHeap, and applies the leak policy when the heap is dropped.
+//
+// It does not mention the types of the heap on purpose. A Drop impl on DHeap<T, I> itself would
+// require every lifetime in T to strictly outlive the heap, which rules out values that hold
+// a DBox into their own heap. Instead, the typed part of the work is erased into `sweep`.
+pub(crate) struct Sweeper {
+    memory: NonNull<()>,
+    sweep: unsafe fn(&Sweeper),
+    policy: LeakPolicy,
+    drop_values: bool,
+
+    #[cfg(feature = "track-allocations")]
+    sites: UnsafeCell<Vec<Option<&'static Location<'static>>>>,
+}
+
+impl Sweeper {
+    /// Takes ownership of the memory of a heap, and returns a pointer to it that stays
+    /// valid until the Sweeper is dropped.
+    pub fn new<T, I: DIndex>(memory: Memory<T, I>) -> (Self, NonNull<Memory<T, I>>) {
+        let memory = NonNull::from(Box::leak(Box::new(memory)));
+
+        let sweeper = Sweeper {
+            memory: memory.cast(),
+            sweep: sweep::<T, I>,
+            policy: LeakPolicy::Leak,
+            drop_values: false,
+
+            #[cfg(feature = "track-allocations")]
+            sites: UnsafeCell::new(Vec::new()),
+        };
+
+        (sweeper, memory)
+    }
+
+    /// Sets the leak policy, which replaces dropping the values.
+    pub fn set_policy(&mut self, policy: LeakPolicy) {
+        self.policy = policy;
+        self.drop_values = false;
+    }
+
+    /// Drops the values instead of applying the leak policy.
+    ///
+    /// The caller must make sure that the values of the heap may be dropped at any point,
+    /// which is the case if they do not borrow anything.
+    pub fn set_drop(&mut self) {
+        self.drop_values = true;
+    }
+
+    /// Remembers where the value in the slot at `index` was allocated.
+    #[cfg(feature = "track-allocations")]
+    pub fn record(&self, index: usize, site: &'static Location<'static>) {
+        let sites = unsafe { &mut *self.sites.get() };
+
+        if sites.len() <= index {
+            sites.resize(index + 1, None);
+        }
+
+        sites[index] = Some(site);
+    }
+
+    /// Moves the allocation site of a value that was relocated by compaction.
+    #[cfg(feature = "track-allocations")]
+    pub fn relocate(&self, from: usize, to: usize) {
+        let site = unsafe { &mut *self.sites.get() }
+            .get_mut(from)
+            .and_then(Option::take);
+
+        if let Some(site) = site {
+            self.record(to, site);
+        }
+    }
+
+    fn site(&self, index: usize) -> Option<&'static Location<'static>> {
+        #[cfg(feature = "track-allocations")]
+        {
+            let sites = unsafe { &*self.sites.get() };
+            sites.get(index).copied().flatten()
+        }
+
+        #[cfg(not(feature = "track-allocations"))]
+        {
+            let _ = index;
+            None
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        // SAFETY: `sweep` was created for the same types as `memory`.
+        unsafe { (self.sweep)(self) }
+    }
+}
+
+// Drops or applies the leak policy to every value that is still in the heap, and frees the memory.
+//
+// SAFETY: `sweeper.memory` must have been created by Sweeper::new::<T, I>().
+unsafe fn sweep<T, I: DIndex>(sweeper: &Sweeper) {
+    // The box is reclaimed first, so the memory is freed even if a destructor panics below.
+    let mut memory = Box::from_raw(sweeper.memory.cast::<Memory<T, I>>().as_ptr());
+
+    if let (LeakPolicy::Leak, false) = (sweeper.policy, sweeper.drop_values) {
+        return;
+    }
+
+    for index in 0..memory.len() {
+        let (slot, tag) = memory.get(index);
+
+        if !(*tag).state().has_value() {
+            continue;
+        }
+
+        if sweeper.drop_values {
+            Slot::value_ptr(slot).drop_in_place();
+            continue;
+        }
+
+        match sweeper.policy {
+            LeakPolicy::Leak => {}
+            LeakPolicy::Report(report) => report(Leak {
+                index,
+                generation: (*tag).generation(),
+                site: sweeper.site(index),
+            }),
+        }
+    }
+}
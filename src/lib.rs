@@ -1,2 +1,21 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "allocator-api2")]
+mod allocator;
 pub mod dheap;
-pub mod tests;
\ No newline at end of file
+pub mod error;
+pub mod index;
+pub mod leak;
+pub mod owned;
+pub mod rc;
+mod slot;
+#[cfg(feature = "serde")]
+pub mod snapshot;
+pub mod static_dheap;
+mod storage;
+#[cfg(feature = "std")]
+pub mod sync;
+pub mod tests;
+pub mod validate;
@@ -0,0 +1,165 @@
+// owned.rs --- owned, lifetime-free handles to values in a shared dense heap.
+
+// Copyright (c) 2023 Sam Belliveau. All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::rc::Rc;
+use core::{
+    borrow::{Borrow, BorrowMut},
+    fmt,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+
+use crate::{
+    dheap::{DHeap, DKey},
+    index::DIndex,
+    slot::State,
+};
+
+/// HeapRef is a pointer that keeps a DHeap alive, such as an `Rc<DHeap<T>>`.
+///
+/// # Safety
+///
+/// Every call to `deref()` must return the same heap, and the heap must not move or be dropped
+/// for as long as the pointer is alive.
+pub unsafe trait HeapRef<T, I: DIndex = usize>: Deref<Target = DHeap<T, I>> {}
+
+unsafe impl<T, I: DIndex> HeapRef<T, I> for Rc<DHeap<T, I>> {}
+
+/// An OwnedDBox is a DBox that owns a pointer to its heap instead of borrowing it.
+///
+/// The heap is kept alive until the last OwnedDBox into it is dropped, so the box has no lifetime
+/// and can be stored in ordinary struct fields and trait objects. The pointer defaults to an
+/// `Rc<DHeap<T, I>>`, and any other `HeapRef` works as well. A DHeap cannot be shared between
+/// threads, so neither can an OwnedDBox. Use a `SyncDHeap` for that instead.
+pub struct OwnedDBox<T, I: DIndex = usize, H: HeapRef<T, I> = Rc<DHeap<T, I>>> {
+    heap: H,
+    key: DKey<I>,
+    value: NonNull<T>,
+}
+
+impl<T, I: DIndex, H: HeapRef<T, I>> OwnedDBox<T, I, H> {
+    /// Allocates memory for the given value `v` in the heap behind `heap`, and returns an `OwnedDBox` pointing to it.
+    ///
+    /// Like `DHeap::new()`, this never moves memory that is already in use.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the heap is poisoned, see `is_poisoned()`, which also happens when its free list is found
+    /// to be corrupted, or if the index type `I` cannot address any more slots.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn new(heap: H, v: T) -> Self {
+        let (key, value) = heap.new(v).into_raw();
+
+        OwnedDBox { heap, key, value }
+    }
+
+    /// Returns the pointer to the heap that the box keeps alive.
+    pub fn heap(this: &Self) -> &H {
+        &this.heap
+    }
+
+    /// Consumes the `OwnedDBox` and retrieves the inner value `T`.
+    ///
+    /// The slot is returned to the heap, and the pointer to the heap is dropped.
+    pub fn into_inner(self) -> T {
+        let this = ManuallyDrop::new(self);
+
+        // SAFETY: The box is the only handle to the value. The pointer to the heap is moved out
+        // once, after the value, so the heap stays alive while the slot is freed.
+        unsafe {
+            let value = this.heap.take(this.key, State::Holding);
+            drop(ptr::read(&this.heap));
+            value.expect("use after free! [corrupted memory]")
+        }
+    }
+}
+
+impl<T, I: DIndex, H: HeapRef<T, I>> Drop for OwnedDBox<T, I, H> {
+    fn drop(&mut self) {
+        // SAFETY: The box is the only handle to the value.
+        if unsafe { self.heap.take(self.key, State::Holding) }.is_none() {
+            self.heap.poison();
+            panic!("double free! [corrupted memory]");
+        }
+    }
+}
+
+impl<T, I: DIndex, H: HeapRef<T, I>> Deref for OwnedDBox<T, I, H> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: The value stays in place for as long as the box exists.
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<T, I: DIndex, H: HeapRef<T, I>> DerefMut for OwnedDBox<T, I, H> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: Same reasoning as in OwnedDBox::deref(), and the box is the only handle to the value.
+        unsafe { self.value.as_mut() }
+    }
+}
+
+impl<T, I: DIndex, H: HeapRef<T, I>> AsRef<T> for OwnedDBox<T, I, H> {
+    fn as_ref(&self) -> &T {
+        self.deref()
+    }
+}
+
+impl<T, I: DIndex, H: HeapRef<T, I>> AsMut<T> for OwnedDBox<T, I, H> {
+    fn as_mut(&mut self) -> &mut T {
+        self.deref_mut()
+    }
+}
+
+impl<T, I: DIndex, H: HeapRef<T, I>> Borrow<T> for OwnedDBox<T, I, H> {
+    fn borrow(&self) -> &T {
+        self.deref()
+    }
+}
+
+impl<T, I: DIndex, H: HeapRef<T, I>> BorrowMut<T> for OwnedDBox<T, I, H> {
+    fn borrow_mut(&mut self) -> &mut T {
+        self.deref_mut()
+    }
+}
+
+/// Cloning an OwnedDBox clones the value into a new slot of the same heap.
+impl<T: Clone, I: DIndex, H: HeapRef<T, I> + Clone> Clone for OwnedDBox<T, I, H> {
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    fn clone(&self) -> Self {
+        OwnedDBox::new(self.heap.clone(), self.deref().clone())
+    }
+}
+
+impl<T: fmt::Debug, I: DIndex, H: HeapRef<T, I>> fmt::Debug for OwnedDBox<T, I, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.deref(), f)
+    }
+}
+
+impl<T: fmt::Display, I: DIndex, H: HeapRef<T, I>> fmt::Display for OwnedDBox<T, I, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.deref(), f)
+    }
+}
@@ -0,0 +1,246 @@
+// rc.rs --- reference-counted handles into the dense heap.
+
+// Copyright (c) 2023 Sam Belliveau. All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use core::{fmt, mem::ManuallyDrop, ops::Deref, ptr::NonNull};
+
+use crate::{
+    dheap::{DHeap, DKey},
+    index::DIndex,
+    slot::State,
+};
+
+/// The reference counts of a slot that is shared through DRc handles.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Counts {
+    pub strong: usize,
+    pub weak: usize,
+}
+
+/// A DRc is a shared handle to a value stored in a DHeap, created with `DHeap::new_rc()`.
+///
+/// It works like `std::rc::Rc`: cloning a DRc hands out another handle to the same value, and the
+/// value is dropped and its slot returned to the free list once the last DRc is dropped. The counts
+/// are kept in a column next to the slots of the heap, so the value itself stays as dense as with a DBox.
+pub struct DRc<'a, T, I: DIndex = usize> {
+    heap: &'a DHeap<T, I>,
+    key: DKey<I>,
+    value: NonNull<T>,
+}
+
+/// A DWeak is a non-owning handle to a value shared through DRc handles, created with `DRc::downgrade()`.
+///
+/// It does not keep the value alive. It refers to the slot by its index and generation, so once
+/// the last DRc is dropped, `upgrade()` returns `None`, even after the slot is reused for another value.
+pub struct DWeak<'a, T, I: DIndex = usize> {
+    heap: &'a DHeap<T, I>,
+    key: DKey<I>,
+}
+
+impl<T, I: DIndex> DHeap<T, I> {
+    /// Allocates memory for the given value `v` in the `DHeap` and returns a `DRc` pointing to it.
+    ///
+    /// Like `new()`, this never moves memory that is already in use.
+    ///
+    /// The reference counts take two `usize`s for every slot up to the highest one that was
+    /// ever shared, on top of the slots themselves. They are only released by `trim()`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the heap is poisoned, see `is_poisoned()`, which also happens when its free list is found
+    /// to be corrupted, or if the index type `I` cannot address any more slots.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn new_rc(&self, v: T) -> DRc<'_, T, I> {
+        let (key, value) = self.new(v).into_raw();
+
+        self.set_counts(key.index(), Counts { strong: 1, weak: 0 });
+
+        DRc {
+            heap: self,
+            key,
+            value,
+        }
+    }
+
+    // Returns the counts of the shared slot behind `key`, or None if its value was dropped.
+    fn shared_counts(&self, key: DKey<I>) -> Option<Counts> {
+        self.resolve(key, State::Holding)
+            .map(|_| self.counts(key.index()))
+            .filter(|counts| counts.strong > 0)
+    }
+}
+
+impl<'a, T, I: DIndex> DRc<'a, T, I> {
+    /// Creates a new DWeak handle to the value.
+    pub fn downgrade(this: &Self) -> DWeak<'a, T, I> {
+        this.update(|counts| counts.weak += 1);
+
+        DWeak {
+            heap: this.heap,
+            key: this.key,
+        }
+    }
+
+    /// Returns the number of DRc handles to the value.
+    pub fn strong_count(this: &Self) -> usize {
+        this.heap.counts(this.key.index()).strong
+    }
+
+    /// Returns the number of DWeak handles to the value.
+    pub fn weak_count(this: &Self) -> usize {
+        this.heap.counts(this.key.index()).weak
+    }
+
+    /// Returns true if both handles point to the same value.
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.value == other.value
+    }
+
+    /// Returns the value if this is the only DRc handle to it, or hands the handle back otherwise.
+    ///
+    /// Any remaining DWeak handles stop upgrading.
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        if DRc::strong_count(&this) != 1 {
+            return Err(this);
+        }
+
+        this.update(|counts| counts.strong = 0);
+        let this = ManuallyDrop::new(this);
+
+        // SAFETY: This was the last handle, so there are no other references to the value.
+        Ok(unsafe { this.heap.take(this.key, State::Holding) }
+            .expect("use after free! [corrupted memory]"))
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Counts)) {
+        let mut counts = self.heap.counts(self.key.index());
+        f(&mut counts);
+        self.heap.set_counts(self.key.index(), counts);
+    }
+}
+
+impl<'a, T, I: DIndex> Clone for DRc<'a, T, I> {
+    fn clone(&self) -> Self {
+        self.update(|counts| counts.strong += 1);
+
+        DRc {
+            heap: self.heap,
+            key: self.key,
+            value: self.value,
+        }
+    }
+}
+
+impl<'a, T, I: DIndex> Drop for DRc<'a, T, I> {
+    fn drop(&mut self) {
+        let mut counts = self.heap.counts(self.key.index());
+        counts.strong -= 1;
+        self.heap.set_counts(self.key.index(), counts);
+
+        if counts.strong == 0 {
+            // SAFETY: This was the last handle, so there are no other references to the value.
+            // The slot is retired along with the value, which stops every DWeak from upgrading.
+            if unsafe { self.heap.take(self.key, State::Holding) }.is_none() {
+                self.heap.poison();
+                panic!("double free! [corrupted memory]");
+            }
+        }
+    }
+}
+
+impl<'a, T, I: DIndex> Deref for DRc<'a, T, I> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: The value stays in place for as long as a DRc to it exists.
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<'a, T, I: DIndex> AsRef<T> for DRc<'a, T, I> {
+    fn as_ref(&self) -> &T {
+        self.deref()
+    }
+}
+
+impl<'a, T: fmt::Debug, I: DIndex> fmt::Debug for DRc<'a, T, I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.deref(), f)
+    }
+}
+
+impl<'a, T: fmt::Display, I: DIndex> fmt::Display for DRc<'a, T, I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.deref(), f)
+    }
+}
+
+impl<'a, T, I: DIndex> DWeak<'a, T, I> {
+    /// Returns a new DRc handle to the value, or `None` if the value has been dropped.
+    pub fn upgrade(&self) -> Option<DRc<'a, T, I>> {
+        let mut counts = self.heap.shared_counts(self.key)?;
+        counts.strong += 1;
+        self.heap.set_counts(self.key.index(), counts);
+
+        Some(DRc {
+            heap: self.heap,
+            key: self.key,
+            value: NonNull::new(self.heap.resolve(self.key, State::Holding)?)?,
+        })
+    }
+
+    /// Returns the number of DRc handles to the value, which is 0 once it has been dropped.
+    pub fn strong_count(&self) -> usize {
+        self.heap
+            .shared_counts(self.key)
+            .map_or(0, |counts| counts.strong)
+    }
+}
+
+impl<'a, T, I: DIndex> Clone for DWeak<'a, T, I> {
+    fn clone(&self) -> Self {
+        if let Some(mut counts) = self.heap.shared_counts(self.key) {
+            counts.weak += 1;
+            self.heap.set_counts(self.key.index(), counts);
+        }
+
+        DWeak {
+            heap: self.heap,
+            key: self.key,
+        }
+    }
+}
+
+impl<'a, T, I: DIndex> Drop for DWeak<'a, T, I> {
+    fn drop(&mut self) {
+        // Once the value is gone, the counts of the slot belong to whatever reuses it.
+        if let Some(mut counts) = self.heap.shared_counts(self.key) {
+            counts.weak -= 1;
+            self.heap.set_counts(self.key.index(), counts);
+        }
+    }
+}
+
+impl<'a, T, I: DIndex> fmt::Debug for DWeak<'a, T, I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(DWeak)")
+    }
+}
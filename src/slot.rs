@@ -0,0 +1,174 @@
+// slot.rs --- compact slot layout shared by the dense heaps.
+
+// Copyright (c) 2023 Sam Belliveau. All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use core::{mem::ManuallyDrop, ptr::addr_of_mut};
+
+/// The State of a slot describes what its Slot<_> currently contains.
+///
+/// It is kept out of band, next to the generation in the slot's Tag,
+/// so that the slot itself can be as small as the value it holds.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum State {
+    /// Edge is always the last slot of the heap. When the
+    /// head points to the edge, new memory must be allocated.
+    Edge = 0,
+
+    /// Empty represents a previously occupied slot that has
+    /// been freed. It links to the previous head when
+    /// it was freed, creating a chain of free blocks for
+    /// future allocations.
+    Empty = 1,
+
+    /// Holding represents a slot that contains a value.
+    /// The value is owned by the DBox<_>, DRc<_> or OwnedDBox<_>
+    /// pointing to it, which is why it is wrapped in a ManuallyDrop<_>.
+    Holding = 2,
+
+    /// When calling DBox.into_inner(), memory is moved out of the
+    /// heap before the DBox<_> has dropped. This serves as an indicator
+    /// for the DBox<_> not to panic when it finds its memory moved during
+    /// the dropping process.
+    Moved = 3,
+
+    /// Keyed represents a slot that contains a value owned by the heap
+    /// itself, which was stored with DHeap.insert(). Only these values
+    /// can be reached through a DKey.
+    Keyed = 4,
+
+    /// Lent represents a slot that was taken without placing a value in it, either
+    /// by a DBoxUninit or as a raw block through the Allocator implementation of the
+    /// heap. Its contents are unknown to the heap, and it stays taken until it is
+    /// initialized or handed back.
+    Lent = 5,
+
+    /// Leaked represents a slot whose value was leaked with DBox.leak().
+    /// The value is borrowed for as long as the heap lives, and the slot
+    /// is never dropped or reused.
+    Leaked = 6,
+}
+
+impl State {
+    /// Returns true if the slot contains a value, whoever owns it.
+    pub(crate) fn has_value(self) -> bool {
+        matches!(self, State::Holding | State::Keyed)
+    }
+}
+
+/// A Tag packs the State of a slot and its generation into 32 bits.
+///
+/// The state takes up the lowest three bits, which leaves 29 bits for the generation.
+/// The generation is bumped every time the slot is freed, wrapping around after 2^29 frees.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Tag(u32);
+
+impl Tag {
+    const STATE_BITS: u32 = 3;
+    const STATE_MASK: u32 = (1 << Self::STATE_BITS) - 1;
+
+    pub const fn new(state: State, generation: u32) -> Self {
+        Tag((generation << Self::STATE_BITS) | state as u32)
+    }
+
+    pub fn state(self) -> State {
+        match self.0 & Self::STATE_MASK {
+            0 => State::Edge,
+            1 => State::Empty,
+            2 => State::Holding,
+            3 => State::Moved,
+            4 => State::Keyed,
+            5 => State::Lent,
+            _ => State::Leaked,
+        }
+    }
+
+    pub fn generation(self) -> u32 {
+        self.0 >> Self::STATE_BITS
+    }
+
+    pub fn with_state(self, state: State) -> Self {
+        Tag((self.0 & !Self::STATE_MASK) | state as u32)
+    }
+
+    /// Moves on to the next generation, so that keys to the current one stop resolving.
+    pub fn retire(self) -> Self {
+        Tag(self.0.wrapping_add(1 << Self::STATE_BITS))
+    }
+}
+
+/// A Slot is the memory for a single element of a dense heap.
+///
+/// Depending on the State in its Tag, it either contains a value, or the link
+/// to the next free slot. Both share the same memory, so a slot is only as large
+/// as the bigger of the two. The link is stored as the index type `I` of the heap.
+pub(crate) union Slot<T, I: Copy = usize> {
+    value: ManuallyDrop<T>,
+    next: I,
+}
+
+impl<T, I: Copy> Slot<T, I> {
+    /// Creates a free slot that links to `next`.
+    pub const fn link(next: I) -> Self {
+        Slot { next }
+    }
+
+    /// Returns a pointer to the value of the slot.
+    ///
+    /// # Safety
+    ///
+    /// `slot` must point to a valid Slot<T>. The value is only initialized while the slot is Holding.
+    pub unsafe fn value_ptr(slot: *mut Self) -> *mut T {
+        addr_of_mut!((*slot).value).cast()
+    }
+
+    /// Returns a pointer to the free list link of the slot.
+    ///
+    /// # Safety
+    ///
+    /// `slot` must point to a valid Slot<T>. The link is only initialized while the slot is Empty.
+    pub unsafe fn next_ptr(slot: *mut Self) -> *mut I {
+        addr_of_mut!((*slot).next)
+    }
+}
+
+/// Called when a box finds that its slot is not Holding a value, which only happens if memory is corrupted.
+///
+/// In debug builds, or with the `checked` feature, this panics with the index of the slot and the
+/// state that was found in it. Otherwise, the compiler is told that this can never be reached,
+/// which keeps the check out of every dereference.
+///
+/// # Safety
+///
+/// Without the checks, reaching this function is undefined behavior.
+#[inline(always)]
+pub(crate) unsafe fn not_holding(index: usize, state: State) -> ! {
+    #[cfg(any(debug_assertions, feature = "checked"))]
+    panic!(
+        "slot {} is {:?} instead of Holding! [corrupted memory]",
+        index, state
+    );
+
+    #[cfg(not(any(debug_assertions, feature = "checked")))]
+    {
+        let _ = (index, state);
+        core::hint::unreachable_unchecked()
+    }
+}
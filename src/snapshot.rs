@@ -0,0 +1,227 @@
+// snapshot.rs --- serde support for dense heap snapshots.
+
+// Copyright (c) 2023 Sam Belliveau. All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::vec::Vec;
+
+use serde::{
+    de::Error as _,
+    ser::{Error as _, SerializeSeq, SerializeStruct},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::{
+    dheap::DHeap,
+    index::DIndex,
+    slot::{Slot, State, Tag},
+    storage::ChunkedVec,
+};
+
+/// A DHeapSnapshot serializes a DHeap slot by slot, created by `DHeap::snapshot()`.
+///
+/// Every slot is written out at its index, together with its generation and the links of the
+/// free list, so deserializing the snapshot into a DHeap restores an identical heap. Any DKey
+/// that was saved alongside the snapshot resolves to the same value after the restore.
+pub struct DHeapSnapshot<'a, T, I: DIndex = usize> {
+    heap: &'a DHeap<T, I>,
+}
+
+impl<T, I: DIndex> DHeap<T, I> {
+    /// Returns a snapshot of the heap that can be serialized with serde.
+    ///
+    /// This takes `&mut self`, so no `DBox` can be alive while the values are being read.
+    /// Values whose `DBox` was forgotten are written out like any other value, and are owned
+    /// by the heap once it is restored, just like the values stored with `insert()`.
+    ///
+    /// The counters of `stats()` are not part of the snapshot. A restored heap starts over
+    /// as if every value in it had just been allocated.
+    pub fn snapshot(&mut self) -> DHeapSnapshot<'_, T, I> {
+        DHeapSnapshot { heap: self }
+    }
+}
+
+// The serialized form of a single slot. Links are always written as a usize,
+// so the format does not depend on the index type of the heap.
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Slot")]
+enum RawSlot<T> {
+    Edge { generation: u32 },
+    Empty { generation: u32, next: usize },
+    Holding { generation: u32, value: T },
+    Leaked { generation: u32, value: T },
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "DHeap")]
+struct RawHeap<T> {
+    head: usize,
+    chunk_size: Option<usize>,
+    slots: Vec<RawSlot<T>>,
+}
+
+struct Slots<'a, T, I: DIndex>(&'a DHeap<T, I>);
+
+impl<'a, T: Serialize, I: DIndex> Serialize for DHeapSnapshot<'a, T, I> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut heap = serializer.serialize_struct("DHeap", 3)?;
+        heap.serialize_field("head", &self.heap.raw_head())?;
+        heap.serialize_field("chunk_size", &self.heap.chunk_size())?;
+        heap.serialize_field("slots", &Slots(self.heap))?;
+        heap.end()
+    }
+}
+
+impl<'a, T: Serialize, I: DIndex> Serialize for Slots<'a, T, I> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut slots = serializer.serialize_seq(Some(self.0.size()))?;
+
+        for index in 0..self.0.size() {
+            let (tag, slot) = self.0.raw_slot(index);
+            let generation = tag.generation();
+
+            // SAFETY: The state of the slot tells which of its fields is initialized,
+            // and the snapshot borrows the heap mutably, so no DBox can write to it.
+            let slot = unsafe {
+                match tag.state() {
+                    State::Edge => RawSlot::Edge { generation },
+                    State::Empty => RawSlot::Empty {
+                        generation,
+                        next: (*Slot::next_ptr(slot)).to_usize(),
+                    },
+                    State::Holding | State::Keyed => RawSlot::Holding {
+                        generation,
+                        value: &*Slot::value_ptr(slot),
+                    },
+                    State::Moved => {
+                        return Err(S::Error::custom(
+                            "value moved out of the heap! [corrupted memory]",
+                        ))
+                    }
+                    State::Lent => {
+                        return Err(S::Error::custom("memory is lent out to an allocator!"))
+                    }
+                    State::Leaked => RawSlot::Leaked {
+                        generation,
+                        value: &*Slot::value_ptr(slot),
+                    },
+                }
+            };
+
+            slots.serialize_element(&slot)?;
+        }
+
+        slots.end()
+    }
+}
+
+/// Restores a heap from a serialized `DHeapSnapshot`.
+///
+/// The snapshot is checked before anything is restored. A snapshot whose `Edge` is missing or out of
+/// place, whose links point outside of the heap or whose indices do not fit in `I` is rejected with an error.
+/// So is a chunk size that cannot be allocated. Once the values are in place, the free list is walked
+/// with `DHeap::validate()`, and a heap whose free list loops, runs into a used slot or misses a free
+/// slot is dropped along with its values and rejected as well.
+///
+/// A snapshot does not record which handle owned a value, so every value comes back as if it had been
+/// stored with `DHeap::insert()`, owned by the heap and reachable through its `DKey`. Like any stored
+/// value, they are leaked when the restored heap is dropped, unless they are removed first, dropped
+/// with `DHeap::clear()`, or the heap is told to drop them with `DHeap::set_drop_on_leak()`.
+impl<'de, T: Deserialize<'de>, I: DIndex> Deserialize<'de> for DHeap<T, I> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = RawHeap::<T>::deserialize(deserializer)?;
+        let len = raw.slots.len();
+
+        let edge = match len.checked_sub(1) {
+            Some(edge) if I::from_usize(edge).is_some() => edge,
+            Some(_) => {
+                return Err(D::Error::custom(
+                    "index out of range for the heap's index type!",
+                ))
+            }
+            None => return Err(D::Error::custom("missing the Edge!")),
+        };
+
+        if raw.chunk_size.is_some_and(|size| size <= 1) {
+            return Err(D::Error::custom("chunk size must be greater than 1!"));
+        }
+
+        if !matches!(
+            raw.slots.get(raw.head),
+            Some(RawSlot::Edge { .. } | RawSlot::Empty { .. })
+        ) {
+            return Err(D::Error::custom("invalid head pointer!"));
+        }
+
+        // Everything is checked before the first value is moved into the heap.
+        for (index, slot) in raw.slots.iter().enumerate() {
+            let (generation, valid) = match *slot {
+                RawSlot::Edge { generation } => (generation, index == edge),
+                RawSlot::Empty { generation, next } => (generation, index != edge && next < len),
+                RawSlot::Holding { generation, .. } | RawSlot::Leaked { generation, .. } => {
+                    (generation, index != edge)
+                }
+            };
+
+            if !valid {
+                return Err(D::Error::custom("invalid slot! [corrupted memory]"));
+            }
+
+            if Tag::new(State::Edge, generation).generation() != generation {
+                return Err(D::Error::custom("generation out of range!"));
+            }
+        }
+
+        // The chunk size comes from the input, so a huge one must fail instead of aborting.
+        let mut memory: ChunkedVec<Slot<T, I>, Tag> = match raw.chunk_size {
+            None => ChunkedVec::flat(len),
+            Some(size) => ChunkedVec::try_chunked(size)
+                .map_err(|_| D::Error::custom("chunk size out of range!"))?,
+        };
+
+        for (index, slot) in raw.slots.into_iter().enumerate() {
+            // Every index was checked against the length, which fits in I.
+            let link = |index| I::from_usize(index).unwrap();
+
+            match slot {
+                RawSlot::Edge { generation } => {
+                    memory.push(Slot::link(link(0)), Tag::new(State::Edge, generation))
+                }
+                RawSlot::Empty { generation, next } => {
+                    memory.push(Slot::link(link(next)), Tag::new(State::Empty, generation))
+                }
+                RawSlot::Holding { generation, value } => {
+                    memory.push(Slot::link(link(0)), Tag::new(State::Keyed, generation));
+
+                    // SAFETY: The slot was just pushed, and is now marked as holding the value.
+                    unsafe { Slot::value_ptr(memory.get(index).0).write(value) };
+                }
+                RawSlot::Leaked { generation, value } => {
+                    memory.push(Slot::link(link(0)), Tag::new(State::Leaked, generation));
+
+                    // SAFETY: Same as above. The value stays leaked, and is never dropped.
+                    unsafe { Slot::value_ptr(memory.get(index).0).write(value) };
+                }
+            }
+        }
+
+        DHeap::from_raw_parts(memory, raw.head).map_err(D::Error::custom)
+    }
+}
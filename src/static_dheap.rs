@@ -0,0 +1,268 @@
+// static_dheap.rs --- fixed-capacity dense heap.
+
+// Copyright (c) 2023 Sam Belliveau. All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use core::{
+    cell::{Cell, UnsafeCell},
+    mem::{forget, MaybeUninit},
+    ops::{Deref, DerefMut, Drop},
+    ptr::drop_in_place,
+};
+
+use crate::{
+    error::AllocError,
+    slot::{not_holding, Slot, State},
+};
+
+/// A StaticDHeap is a dense heap with a fixed capacity of `N` elements, stored inline.
+///
+/// It uses the same free list as a DHeap, but its memory is a plain array that is part of the
+/// struct itself, so it never touches the global allocator. Once all `N` slots are in use,
+/// allocations fail with `AllocError::CapacityExhausted` until a slot is freed. This makes the
+/// memory footprint of the heap known at compile time.
+///
+/// Slots past the highest one ever used are left uninitialized, and the end of the used
+/// slots plays the role of the `Edge` in a DHeap. Since there are no keys into a StaticDHeap,
+/// it only keeps one byte of state per slot, without a generation.
+///
+/// Like a DHeap, the heap is poisoned once it finds its own metadata in an inconsistent state,
+/// and refuses to allocate until the poison is cleared, see `is_poisoned()`.
+pub struct StaticDHeap<T, const N: usize> {
+    buffer: UnsafeCell<[MaybeUninit<Slot<T>>; N]>,
+    states: [Cell<State>; N],
+    len: Cell<usize>,
+    head: Cell<usize>,
+    poisoned: Cell<bool>,
+}
+
+impl<T, const N: usize> StaticDHeap<T, N> {
+    /// Creates a new, empty `StaticDHeap`.
+    pub const fn empty() -> Self {
+        StaticDHeap {
+            buffer: UnsafeCell::new([const { MaybeUninit::uninit() }; N]),
+            states: [const { Cell::new(State::Edge) }; N],
+            len: Cell::new(0),
+            head: Cell::new(0),
+            poisoned: Cell::new(false),
+        }
+    }
+
+    // Raw pointer to a slot, which avoids creating references to its neighbours.
+    fn slot(&self, index: usize) -> *mut Slot<T> {
+        assert!(
+            index < self.len.get(),
+            "index out of bounds! [corrupted memory]"
+        );
+
+        // SAFETY: MaybeUninit<Slot<T>> has the same layout as Slot<T>.
+        unsafe { (self.buffer.get() as *mut Slot<T>).add(index) }
+    }
+
+    fn value(&self, index: usize) -> *mut T {
+        unsafe { Slot::value_ptr(self.slot(index)) }
+    }
+
+    /// Allocates memory for the given value `v` in the `StaticDHeap` and returns a `StaticDBox` pointing to it.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(StaticDBox<T, N>)` if the allocation was successful.
+    /// - `Err(AllocError::CapacityExhausted(v))` if all `N` slots are in use.
+    /// - `Err(AllocError::Poisoned(v))` if the heap is poisoned, or its free list turns out to be corrupted.
+    pub fn safe_new(&self, v: T) -> Result<StaticDBox<'_, T, N>, AllocError<T>> {
+        if self.is_poisoned() {
+            return Err(AllocError::Poisoned(v));
+        }
+
+        let index = self.head.get();
+
+        if index == self.len.get() {
+            if index == N {
+                return Err(AllocError::CapacityExhausted(v));
+            }
+
+            self.len.set(index + 1);
+            self.head.set(index + 1);
+        } else {
+            match self.states[index].get() {
+                State::Empty => self.head.set(unsafe { *Slot::next_ptr(self.slot(index)) }),
+                _ => {
+                    self.poison();
+                    return Err(AllocError::Poisoned(v));
+                }
+            }
+        }
+
+        unsafe { self.value(index).write(v) };
+        self.states[index].set(State::Holding);
+
+        Ok(StaticDBox { heap: self, index })
+    }
+
+    // Overwrites the state of a slot, so that tests can break the heap on purpose.
+    #[cfg(all(test, any(debug_assertions, feature = "checked")))]
+    pub(crate) fn corrupt_state(&self, index: usize, state: State) {
+        self.states[index].set(state);
+    }
+
+    /// Retrieves the number of slots that have been used so far.
+    pub fn size(&self) -> usize {
+        self.len.get()
+    }
+
+    /// Returns the number of values the heap is able to hold, which is always `N`.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Returns true if the heap has found its own metadata in an inconsistent state.
+    ///
+    /// A poisoned heap keeps working for the values that are already in it, but `safe_new()`
+    /// returns `AllocError::Poisoned` until the poison is cleared.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.get()
+    }
+
+    /// Clears the poisoned state of the heap, so that it allocates again.
+    ///
+    /// The heap is not repaired in any way.
+    pub fn clear_poison(&mut self) {
+        self.poisoned.set(false);
+    }
+
+    fn poison(&self) {
+        self.poisoned.set(true);
+    }
+}
+
+impl<T, const N: usize> Default for StaticDHeap<T, N> {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+/// StaticDBox is the smart pointer handed out by a StaticDHeap.
+///
+/// It behaves exactly like a DBox, returning its slot to the heap when it is dropped.
+pub struct StaticDBox<'a, T, const N: usize> {
+    heap: &'a StaticDHeap<T, N>,
+    index: usize,
+}
+
+impl<'a, T, const N: usize> StaticDBox<'a, T, N> {
+    fn state(&self) -> &'a Cell<State> {
+        &self.heap.states[self.index]
+    }
+
+    /// Consumes the `StaticDBox` and retrieves the inner value `T`.
+    pub fn into_inner(self) -> T {
+        match self.state().replace(State::Moved) {
+            State::Holding => unsafe { self.heap.value(self.index).read() },
+            _ => {
+                self.heap.poison();
+                panic!("use after free! [corrupted memory]");
+            }
+        }
+    }
+}
+
+impl<'a, T, const N: usize> Drop for StaticDBox<'a, T, N> {
+    fn drop(&mut self) {
+        let release = Release {
+            heap: self.heap,
+            index: self.index,
+        };
+
+        match self.state().get() {
+            State::Holding => {
+                // The value is marked as moved out before it is dropped, so that
+                // the slot is never seen holding a value that is half dropped.
+                self.state().set(State::Moved);
+
+                // SAFETY: The slot was holding the value, and is freed by the release right after
+                // it is dropped, which also happens while unwinding if the destructor panics.
+                unsafe { drop_in_place(self.heap.value(self.index)) }
+            }
+            State::Moved => {}
+            _ => {
+                forget(release);
+                self.heap.poison();
+                panic!("double free! [corrupted memory]");
+            }
+        }
+
+        drop(release);
+    }
+}
+
+// Release returns a slot to the free list when it goes out of scope,
+// so that the slot is not lost when the destructor of its value panics.
+struct Release<'a, T, const N: usize> {
+    heap: &'a StaticDHeap<T, N>,
+    index: usize,
+}
+
+impl<'a, T, const N: usize> Drop for Release<'a, T, N> {
+    fn drop(&mut self) {
+        unsafe { *Slot::next_ptr(self.heap.slot(self.index)) = self.heap.head.replace(self.index) };
+        self.heap.states[self.index].set(State::Empty);
+    }
+}
+
+impl<'a, T, const N: usize> Deref for StaticDBox<'a, T, N> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        let state = self.state().get();
+
+        if state == State::Holding {
+            unsafe { &*self.heap.value(self.index) }
+        } else {
+            // SAFETY: Same reasoning as in DBox::deref().
+            unsafe { not_holding(self.index, state) }
+        }
+    }
+}
+
+impl<'a, T, const N: usize> DerefMut for StaticDBox<'a, T, N> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        let state = self.state().get();
+
+        if state == State::Holding {
+            unsafe { &mut *self.heap.value(self.index) }
+        } else {
+            // SAFETY: Same reasoning as in DBox::deref().
+            unsafe { not_holding(self.index, state) }
+        }
+    }
+}
+
+impl<'a, T, const N: usize> AsRef<T> for StaticDBox<'a, T, N> {
+    fn as_ref(&self) -> &T {
+        self.deref()
+    }
+}
+
+impl<'a, T, const N: usize> AsMut<T> for StaticDBox<'a, T, N> {
+    fn as_mut(&mut self) -> &mut T {
+        self.deref_mut()
+    }
+}
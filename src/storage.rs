@@ -0,0 +1,328 @@
+// storage.rs --- chunked backing storage for the dense heap.
+
+// Copyright (c) 2023 Sam Belliveau. All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::{collections::TryReserveError, vec, vec::Vec};
+
+/// Marks a ChunkedVec that is still made out of a single growable vector.
+const FLAT: usize = usize::MAX;
+
+/// ChunkedVec is the backing store of the DHeap.
+///
+/// Every element is made out of a node `N` and its metadata `M`, which are stored in two
+/// separate vectors so that neither has to be padded to the alignment of the other.
+///
+/// It starts out as a single vector, which keeps the nodes contiguous and lets them be
+/// grown in place. Once it is asked to grow without moving anything, it freezes the capacity
+/// of that vector and continues in chunks of the same size. Each chunk is allocated once
+/// and never moved afterwards, so pointers into the chunks stay valid as the storage grows.
+/// Chunks may be allocated ahead of time, in which case they sit empty after the last node.
+pub(crate) struct ChunkedVec<N, M> {
+    chunks: Vec<Chunk<N, M>>,
+    chunk_size: usize,
+    len: usize,
+
+    // The capacity a flat storage was created with, which it is never shrunk below.
+    // Otherwise a storage that was shrunk right before it turns into chunks would
+    // end up with tiny chunks.
+    min_capacity: usize,
+}
+
+/// A Chunk keeps the nodes and their metadata side by side.
+struct Chunk<N, M> {
+    nodes: Vec<N>,
+    meta: Vec<M>,
+}
+
+impl<N, M> Chunk<N, M> {
+    fn with_capacity(capacity: usize) -> Self {
+        Chunk {
+            nodes: Vec::with_capacity(capacity),
+            meta: Vec::with_capacity(capacity),
+        }
+    }
+
+    fn try_with_capacity(capacity: usize) -> Result<Self, TryReserveError> {
+        let mut chunk = Chunk {
+            nodes: Vec::new(),
+            meta: Vec::new(),
+        };
+
+        chunk.nodes.try_reserve_exact(capacity)?;
+        chunk.meta.try_reserve_exact(capacity)?;
+        Ok(chunk)
+    }
+
+    // The two vectors may round their capacities differently,
+    // so only the smaller one is safe to fill without allocating.
+    fn capacity(&self) -> usize {
+        self.nodes.capacity().min(self.meta.capacity())
+    }
+
+    fn push(&mut self, node: N, meta: M) {
+        self.nodes.push(node);
+        self.meta.push(meta);
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.nodes.reserve(additional);
+        self.meta.reserve(additional);
+    }
+
+    fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.nodes.try_reserve(additional)?;
+        self.meta.try_reserve(additional)
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.nodes.truncate(len);
+        self.meta.truncate(len);
+    }
+
+    fn shrink_to(&mut self, min_capacity: usize) {
+        self.nodes.shrink_to(min_capacity);
+        self.meta.shrink_to(min_capacity);
+    }
+}
+
+impl<N, M> ChunkedVec<N, M> {
+    /// Creates a single growable vector with room for `capacity` nodes.
+    pub fn flat(capacity: usize) -> Self {
+        ChunkedVec {
+            chunks: vec![Chunk::with_capacity(capacity)],
+            chunk_size: FLAT,
+            len: 0,
+            min_capacity: capacity,
+        }
+    }
+
+    /// Creates storage that is made out of chunks holding `chunk_size` nodes each.
+    pub fn chunked(chunk_size: usize) -> Self {
+        assert!(chunk_size > 0);
+
+        ChunkedVec {
+            chunks: vec![Chunk::with_capacity(chunk_size)],
+            chunk_size,
+            len: 0,
+            min_capacity: chunk_size,
+        }
+    }
+
+    /// Like chunked(), but reports allocation failures instead of aborting.
+    #[cfg(feature = "serde")]
+    pub fn try_chunked(chunk_size: usize) -> Result<Self, TryReserveError> {
+        assert!(chunk_size > 0);
+
+        Ok(ChunkedVec {
+            chunks: vec![Chunk::try_with_capacity(chunk_size)?],
+            chunk_size,
+            len: 0,
+            min_capacity: chunk_size,
+        })
+    }
+
+    /// The number of nodes in the storage.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The number of nodes the storage can hold before it has to allocate.
+    pub fn capacity(&self) -> usize {
+        match self.chunk_size {
+            FLAT => self.chunks[0].capacity(),
+            // Every chunk was allocated with room for at least chunk_size nodes.
+            size => self.chunks.len() * size,
+        }
+    }
+
+    /// The size of each chunk, or `None` while the storage is a single vector.
+    pub fn chunk_size(&self) -> Option<usize> {
+        (self.chunk_size != FLAT).then_some(self.chunk_size)
+    }
+
+    /// Returns true if the next push has to allocate.
+    pub fn is_full(&self) -> bool {
+        self.len == self.capacity()
+    }
+
+    /// Returns raw pointers to the node at `index` and its metadata.
+    ///
+    /// The pointers stay valid until the node is moved by push() on a flat storage.
+    pub fn get(&mut self, index: usize) -> (*mut N, *mut M) {
+        assert!(index < self.len, "index out of bounds! [corrupted memory]");
+
+        let (chunk, offset) = match self.chunk_size {
+            FLAT => (0, index),
+            size => (index / size, index % size),
+        };
+
+        // SAFETY: The index was checked against the length, and every chunk
+        // before the one being filled holds exactly chunk_size nodes.
+        // as_mut_ptr() does not create a reference to the other nodes in the chunk.
+        unsafe {
+            let chunk = self.chunks.get_unchecked_mut(chunk);
+            (
+                chunk.nodes.as_mut_ptr().add(offset),
+                chunk.meta.as_mut_ptr().add(offset),
+            )
+        }
+    }
+
+    /// Returns the index of the node that `node` points to, or `None` if it points outside of the storage.
+    #[cfg(feature = "allocator-api2")]
+    pub fn index_of(&self, node: *const N) -> Option<usize> {
+        let size = core::mem::size_of::<N>();
+
+        self.chunks.iter().enumerate().find_map(|(chunk, nodes)| {
+            let start = nodes.nodes.as_ptr() as usize;
+            let offset = (node as usize).checked_sub(start)? / size;
+
+            let first = match self.chunk_size {
+                FLAT => 0,
+                size => chunk * size,
+            };
+
+            (offset < nodes.nodes.len()).then_some(first + offset)
+        })
+    }
+
+    /// Appends a node, growing a flat storage in place.
+    ///
+    /// On a flat storage this may move every node, invalidating all pointers into it.
+    pub fn push(&mut self, node: N, meta: M) {
+        if self.chunk_size == FLAT {
+            self.chunks[0].push(node, meta);
+            self.len += 1;
+        } else {
+            self.push_stable(node, meta);
+        }
+    }
+
+    /// Appends a node without moving any of the existing ones.
+    ///
+    /// A flat storage that is full is turned into chunks of its current capacity.
+    pub fn push_stable(&mut self, node: N, meta: M) {
+        if self.is_full() {
+            self.reserve_stable(1);
+        }
+
+        match self.chunk_size {
+            FLAT => self.chunks[0].push(node, meta),
+            size => self.chunks[self.len / size].push(node, meta),
+        }
+
+        self.len += 1;
+    }
+
+    /// Makes room for at least `additional` more nodes without moving any of the existing ones.
+    ///
+    /// A flat storage without enough room is turned into chunks of its current capacity,
+    /// and as many chunks as needed are allocated up front.
+    pub fn reserve_stable(&mut self, additional: usize) {
+        let required = self
+            .len
+            .checked_add(additional)
+            .expect("capacity overflow!");
+
+        if required <= self.capacity() {
+            return;
+        }
+
+        if self.chunk_size == FLAT {
+            // An empty vector has no nodes that could be moved, so it may simply grow.
+            if self.len == 0 {
+                self.chunks[0].nodes.reserve_exact(additional);
+                self.chunks[0].meta.reserve_exact(additional);
+                return;
+            }
+
+            self.chunk_size = self.chunks[0].capacity();
+        }
+
+        while self.capacity() < required {
+            self.chunks.push(Chunk::with_capacity(self.chunk_size));
+        }
+    }
+
+    /// Makes room for at least `additional` more nodes, growing a flat storage in place.
+    ///
+    /// On a flat storage this may move every node, invalidating all pointers into it.
+    pub fn reserve(&mut self, additional: usize) {
+        match self.chunk_size {
+            FLAT => self.chunks[0].reserve(additional),
+            _ => self.reserve_stable(additional),
+        }
+    }
+
+    /// Like reserve(), but reports allocation failures instead of aborting.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        let size = match self.chunk_size {
+            FLAT => return self.chunks[0].try_reserve(additional),
+            size => size,
+        };
+
+        let missing = self
+            .len
+            .saturating_add(additional)
+            .saturating_sub(self.capacity());
+
+        // An overflowing request asks for more chunks than can be addressed, which fails here.
+        self.chunks.try_reserve(missing.div_ceil(size))?;
+
+        while self.capacity() < self.len + additional {
+            self.chunks.push(Chunk::try_with_capacity(size)?);
+        }
+
+        Ok(())
+    }
+
+    /// Shortens the storage to `len` nodes, dropping the rest.
+    ///
+    /// Chunks past the new end are released, but the last remaining chunk keeps its memory.
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
+        }
+
+        match self.chunk_size {
+            FLAT => self.chunks[0].truncate(len),
+            size => {
+                // Reserved chunks past the new end are released as well.
+                let chunks = len.div_ceil(size).max(1);
+                self.chunks.truncate(chunks);
+                self.chunks[chunks - 1].truncate(len - (chunks - 1) * size);
+            }
+        }
+
+        self.len = len;
+    }
+
+    /// Releases as much unused memory as possible without breaking up the chunks.
+    ///
+    /// A flat storage is shrunk down to its length, but never below the capacity it was
+    /// created with. This may move every node.
+    pub fn shrink_to_fit(&mut self) {
+        match self.chunk_size {
+            FLAT => self.chunks[0].shrink_to(self.min_capacity),
+            _ => self.chunks.shrink_to_fit(),
+        }
+    }
+}
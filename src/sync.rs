@@ -0,0 +1,194 @@
+// sync.rs --- thread-safe dense heap.
+
+// Copyright (c) 2023 Sam Belliveau. All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::{
+    mem::forget,
+    ops::{Deref, DerefMut, Drop},
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+    thread::available_parallelism,
+};
+
+use crate::dheap::{DHeap, DKey};
+
+/// A SyncDHeap is a dense heap that can be shared between threads.
+///
+/// It is made out of several shards, each of which is a chunked DHeap behind its own mutex.
+/// Allocations are spread over the shards in a round-robin fashion, so threads that allocate
+/// at the same time rarely wait on the same lock. Since the shards are chunked, values never
+/// move, and a SyncDBox can access its value without taking any lock at all. Locks are only
+/// held while a slot is claimed or returned to the free list.
+pub struct SyncDHeap<T> {
+    shards: Box<[Mutex<DHeap<T>>]>,
+    next: AtomicUsize,
+}
+
+impl<T> SyncDHeap<T> {
+    /// Creates a new `SyncDHeap` with one shard for every thread the machine can run in parallel.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunk_size` - The number of elements in each chunk of every shard.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is less than or equal to 1.
+    pub fn with_chunk_size(chunk_size: usize) -> Self {
+        let shards = available_parallelism().map(|n| n.get()).unwrap_or(1);
+        Self::with_shards(shards, chunk_size)
+    }
+
+    /// Creates a new `SyncDHeap` with a specific number of shards.
+    ///
+    /// # Arguments
+    ///
+    /// * `shards` - The number of independently locked heaps.
+    /// * `chunk_size` - The number of elements in each chunk of every shard.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is 0, or if `chunk_size` is less than or equal to 1.
+    pub fn with_shards(shards: usize, chunk_size: usize) -> Self {
+        assert!(shards > 0);
+
+        SyncDHeap {
+            shards: (0..shards)
+                .map(|_| Mutex::new(DHeap::with_chunk_size(chunk_size)))
+                .collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    // A poisoned shard only means that a panic happened while it was locked.
+    // The heap never holds the lock while running user code, so it is still consistent.
+    fn shard(&self, shard: usize) -> MutexGuard<'_, DHeap<T>> {
+        self.shards[shard]
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Allocates memory for the given value `v` and returns a `SyncDBox` pointing to it.
+    ///
+    /// Like `DHeap::new()`, this never moves values that are already in the heap.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the free list of the chosen shard is corrupted.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(&self, v: T) -> SyncDBox<'_, T> {
+        let shard = self.next.fetch_add(1, Ordering::Relaxed) % self.shards.len();
+
+        let mut heap = self.shard(shard);
+        let key = heap.insert(v);
+        let value = NonNull::from(
+            heap.get_mut(key)
+                .expect("use after free! [corrupted memory]"),
+        );
+
+        SyncDBox {
+            heap: self,
+            shard,
+            key,
+            value,
+        }
+    }
+
+    /// Retrieves the current memory usage of the `SyncDHeap`, summed over all shards.
+    pub fn size(&self) -> usize {
+        (0..self.shards.len()).map(|i| self.shard(i).size()).sum()
+    }
+
+    /// Returns the number of shards in the heap.
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    // Takes the value out of its shard, and returns the slot to the free list.
+    fn take(&self, shard: usize, key: DKey) -> T {
+        self.shard(shard)
+            .remove(key)
+            .expect("double free! [corrupted memory]")
+    }
+}
+
+/// SyncDBox is the thread-safe counterpart of DBox, pointing into a SyncDHeap.
+///
+/// It can be sent to other threads when `T: Send`, and shared between them when `T: Sync`.
+pub struct SyncDBox<'a, T> {
+    heap: &'a SyncDHeap<T>,
+    shard: usize,
+    key: DKey,
+    value: NonNull<T>,
+}
+
+// SAFETY: A SyncDBox uniquely owns its value, just like a Box<T>. The shard that
+// stores the value is only touched while its lock is held.
+unsafe impl<'a, T: Send> Send for SyncDBox<'a, T> {}
+unsafe impl<'a, T: Send + Sync> Sync for SyncDBox<'a, T> {}
+
+impl<'a, T> SyncDBox<'a, T> {
+    /// Consumes the `SyncDBox` and retrieves the inner value `T`.
+    pub fn into_inner(self) -> T {
+        let value = self.heap.take(self.shard, self.key);
+        forget(self);
+        value
+    }
+}
+
+impl<'a, T> Drop for SyncDBox<'a, T> {
+    fn drop(&mut self) {
+        // The value is dropped after the lock is released, as its destructor
+        // may very well free other boxes that live in the same shard.
+        drop(self.heap.take(self.shard, self.key));
+    }
+}
+
+impl<'a, T> Deref for SyncDBox<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: The shards are chunked, so the value never moves while the box is alive.
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<'a, T> DerefMut for SyncDBox<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: The shards are chunked, so the value never moves while the box is alive.
+        unsafe { self.value.as_mut() }
+    }
+}
+
+impl<'a, T> AsRef<T> for SyncDBox<'a, T> {
+    fn as_ref(&self) -> &T {
+        self.deref()
+    }
+}
+
+impl<'a, T> AsMut<T> for SyncDBox<'a, T> {
+    fn as_mut(&mut self) -> &mut T {
+        self.deref_mut()
+    }
+}
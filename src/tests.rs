@@ -1,6 +1,13 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
     use crate::dheap::*;
+    use crate::error::*;
+    use crate::owned::*;
+    use crate::rc::*;
+    use crate::static_dheap::*;
+    #[cfg(feature = "std")]
+    use crate::sync::*;
 
     #[test]
     fn create_dheap() {
@@ -106,4 +113,1031 @@ mod tests {
 
         println!("Final Size {}", heap.size());
     }
+
+    #[test]
+    fn safe_new_returns_rejected_value() {
+        let heap: DHeap<String> = DHeap::with_capacity(2);
+
+        let _a = heap.safe_new("a".to_string()).unwrap();
+        let _b = heap.safe_new("b".to_string()).unwrap();
+
+        match heap.safe_new("c".to_string()) {
+            Err(AllocError::CapacityExhausted(value)) => assert_eq!(value, "c"),
+            _ => panic!("expected the heap to be out of reserved memory"),
+        }
+
+        assert_eq!(heap.size(), 3);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn alloc_error_is_an_error() {
+        let error: Box<dyn std::error::Error> = Box::new(AllocError::CapacityExhausted(5));
+        assert_eq!(error.to_string(), "out of reserved memory!");
+
+        let error = AllocError::Poisoned(vec![1, 2, 3]);
+        assert_eq!(format!("{:?}", error), "Poisoned(..)");
+        assert_eq!(error.into_inner(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn chunked_new_keeps_references() {
+        let heap: DHeap<u64> = DHeap::with_chunk_size(4);
+        assert_eq!(heap.chunk_size(), Some(4));
+
+        let first = heap.new(7);
+        let value: &u64 = &first;
+
+        let boxes: Vec<_> = (0..100).map(|i| heap.new(i)).collect();
+
+        assert_eq!(*value, 7);
+        assert!(std::ptr::eq(value, &*first));
+        assert_eq!(heap.size(), 102);
+
+        for (i, dbox) in boxes.iter().enumerate() {
+            assert_eq!(**dbox, i as u64);
+        }
+    }
+
+    #[test]
+    fn new_grows_flat_heap_into_chunks() {
+        let heap: DHeap<i32> = DHeap::with_capacity(3);
+        assert_eq!(heap.chunk_size(), None);
+
+        let first = heap.new(1);
+        let value: &i32 = &first;
+
+        let boxes: Vec<_> = (0..10).map(|i| heap.new(i)).collect();
+        assert_eq!(heap.chunk_size(), Some(4));
+        assert!(std::ptr::eq(value, &*first));
+
+        // Freed slots are reused before any new chunk is started.
+        drop(boxes);
+        let _again: Vec<_> = (0..10).map(|i| heap.safe_new(i).unwrap()).collect();
+        assert_eq!(heap.size(), 12);
+    }
+
+    #[test]
+    fn keys_insert_get_remove() {
+        let mut heap: DHeap<String> = DHeap::with_capacity(4);
+
+        let a = heap.insert("a".to_string());
+        let b = heap.insert("b".to_string());
+
+        assert_eq!(heap.get(a).map(String::as_str), Some("a"));
+        heap.get_mut(b).unwrap().push('!');
+        assert_eq!(heap.get(b).map(String::as_str), Some("b!"));
+
+        assert_eq!(heap.remove(a), Some("a".to_string()));
+        assert_eq!(heap.remove(a), None);
+        assert!(!heap.contains_key(a));
+        assert!(heap.contains_key(b));
+
+        heap.clear();
+        assert!(!heap.contains_key(b));
+        assert_eq!(heap.stats().live, 0);
+    }
+
+    #[test]
+    fn stale_key_after_reuse() {
+        let mut heap: DHeap<i32> = DHeap::with_capacity(4);
+
+        let old = heap.insert(1);
+        heap.remove(old);
+
+        // The freed slot is reused through the free list.
+        let new = heap.insert(2);
+        assert_eq!(new.index(), old.index());
+        assert_ne!(new.generation(), old.generation());
+
+        assert_eq!(heap.get(old), None);
+        assert_eq!(heap.get_mut(old), None);
+        assert_eq!(heap.get(new), Some(&2));
+
+        // Slots reused by a DBox do not resolve through stale keys either.
+        heap.remove(new);
+        let dbox = heap.safe_new(3).unwrap();
+        assert_eq!(heap.get(new), None);
+        assert_eq!(*dbox, 3);
+    }
+
+    #[test]
+    fn keys_never_resolve_to_handles() {
+        let mut keyed: DHeap<String> = DHeap::with_capacity(4);
+        let boxed: DHeap<String> = DHeap::with_capacity(4);
+
+        // Both slots have the same index and generation, in different heaps.
+        let key = keyed.insert(String::from("keyed"));
+        let dbox = boxed.new(String::from("boxed"));
+        let rc = boxed.new_rc(String::from("shared"));
+
+        assert_eq!(boxed.get(key), None);
+        assert!(!boxed.contains_key(key));
+        assert_eq!(keyed.remove(key).as_deref(), Some("keyed"));
+        assert_eq!((dbox.as_str(), rc.as_str()), ("boxed", "shared"));
+    }
+
+    #[test]
+    fn dbox_into_key() {
+        let mut heap: DHeap<i32> = DHeap::with_capacity(4);
+
+        let key = heap.safe_new(5).unwrap().into_key();
+        assert_eq!(heap.get(key), Some(&5));
+        assert_eq!(heap.remove(key), Some(5));
+        assert_eq!(heap.size(), 2);
+    }
+
+    #[test]
+    fn iterate_live_values() {
+        let mut heap: DHeap<i32> = DHeap::with_capacity(8);
+
+        let keys: Vec<_> = (0..6).map(|i| heap.insert(i)).collect();
+        heap.remove(keys[1]);
+        heap.remove(keys[4]);
+
+        // A forgotten box is still live, and is visited as well. It reuses the last freed slot.
+        std::mem::forget(heap.safe_new(10).unwrap());
+
+        assert_eq!(
+            heap.iter().copied().collect::<Vec<_>>(),
+            vec![0, 2, 3, 10, 5]
+        );
+
+        for value in heap.iter_mut() {
+            *value *= 2;
+        }
+
+        let entries: Vec<_> = heap
+            .entries()
+            .map(|(key, value)| (key.index(), *value))
+            .collect();
+        assert_eq!(entries, vec![(0, 0), (2, 4), (3, 6), (4, 20), (5, 10)]);
+
+        for (key, value) in heap.entries_mut() {
+            *value += key.index() as i32;
+        }
+
+        assert_eq!(heap.get(keys[5]), Some(&15));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn sync_heap_across_threads() {
+        let heap: SyncDHeap<Vec<usize>> = SyncDHeap::with_shards(4, 8);
+
+        let boxes: Vec<_> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..8)
+                .map(|t| {
+                    let heap = &heap;
+                    scope.spawn(move || {
+                        let mut mine = Vec::new();
+                        for i in 0..100 {
+                            let dbox = heap.new(vec![t, i]);
+                            if i % 2 == 0 {
+                                mine.push(dbox);
+                            }
+                        }
+                        mine
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .flat_map(|w| w.join().unwrap())
+                .collect()
+        });
+
+        assert_eq!(boxes.len(), 400);
+        for dbox in &boxes {
+            assert_eq!(dbox[1] % 2, 0);
+        }
+
+        // Boxes can be dropped from a different thread than the one that made them.
+        std::thread::scope(|scope| {
+            scope.spawn(move || drop(boxes));
+        });
+
+        let dbox = heap.new(vec![1]);
+        assert_eq!(dbox.into_inner(), vec![1]);
+    }
+
+    #[test]
+    fn heap_stats() {
+        let heap: DHeap<i32> = DHeap::with_capacity(8);
+        assert_eq!(heap.stats().capacity, 8);
+
+        let boxes: Vec<_> = (0..5).map(|i| heap.safe_new(i).unwrap()).collect();
+        let mut boxes = boxes.into_iter();
+
+        drop(boxes.next());
+        drop(boxes.next());
+        assert_eq!(boxes.next().unwrap().into_inner(), 2);
+
+        let stats = heap.stats();
+        assert_eq!(stats.live, 2);
+        assert_eq!(stats.free, 3);
+        assert_eq!(stats.moved, 0);
+        assert_eq!(stats.peak, 5);
+        assert_eq!(stats.allocations, 5);
+        assert_eq!(stats.frees, 3);
+        assert_eq!(stats.fragmentation(), 0.6);
+
+        let _again = heap.safe_new(10).unwrap();
+        let stats = heap.stats();
+        assert_eq!((stats.live, stats.free, stats.peak), (3, 2, 5));
+    }
+
+    #[test]
+    fn compact_relocates_keys() {
+        let mut heap: DHeap<i32> = DHeap::with_capacity(16);
+
+        let mut keys: Vec<_> = (0..10).map(|i| heap.insert(i)).collect();
+        for i in [0, 2, 3, 7, 8] {
+            heap.remove(keys[i]);
+        }
+
+        assert_eq!(heap.size(), 11);
+
+        let mut moves = Vec::new();
+        let released = heap.compact_with(|old, new| moves.push((old, new)));
+
+        assert_eq!(released, 5);
+        assert_eq!(heap.size(), 6);
+        assert_eq!(heap.stats().free, 0);
+
+        for (old, new) in moves {
+            assert_eq!(heap.get(old), None);
+            let key = keys.iter_mut().find(|key| **key == old).unwrap();
+            *key = new;
+        }
+
+        for i in [1, 4, 5, 6, 9] {
+            assert_eq!(heap.get(keys[i]), Some(&(i as i32)));
+        }
+
+        // Released slots come back with fresh generations.
+        let reused = heap.insert(100);
+        assert_eq!(reused.index(), 5);
+        assert_eq!(heap.get(keys[7]), None);
+        assert_eq!(heap.get(reused), Some(&100));
+
+        // The heap keeps the memory it was created with, so it later grows in chunks of that size.
+        let boxes: Vec<_> = (0..20).map(|i| heap.new(i)).collect();
+        assert_eq!(heap.chunk_size(), Some(17));
+        assert_eq!(*boxes[19], 19);
+    }
+
+    #[test]
+    fn compact_chunked_heap() {
+        let mut heap: DHeap<u8> = DHeap::with_chunk_size(4);
+
+        let keys: Vec<_> = (0..20).map(|i| heap.insert(i)).collect();
+        for key in &keys[1..] {
+            heap.remove(*key);
+        }
+
+        assert_eq!(heap.compact(), 19);
+        assert_eq!(heap.size(), 2);
+        assert_eq!(heap.get(keys[0]), Some(&0));
+
+        let more: Vec<_> = (0..10).map(|i| heap.new(i)).collect();
+        assert_eq!(*more[9], 9);
+    }
+
+    #[test]
+    fn compact_survives_panicking_callback() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let mut heap: DHeap<i32> = DHeap::with_capacity(8);
+
+        let keys: Vec<_> = (0..6).map(|i| heap.insert(i)).collect();
+        for key in &keys[..3] {
+            heap.remove(*key);
+        }
+
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            heap.compact_with(|_, _| panic!("boom"));
+        }));
+        assert!(result.is_err());
+
+        // The first value was moved before the callback panicked, and the heap was trimmed behind it.
+        assert!(heap.validate().is_ok());
+        assert_eq!(heap.size(), 6);
+        assert_eq!(heap.get(keys[5]), None);
+
+        let mut values: Vec<_> = heap.entries().map(|(_, v)| *v).collect();
+        values.sort();
+        assert_eq!(values, [3, 4, 5]);
+
+        let key = heap.insert(6);
+        assert_eq!(heap.get(key), Some(&6));
+        assert_eq!(heap.stats().free, 1);
+    }
+
+    #[test]
+    fn trim_releases_free_suffix() {
+        let mut heap: DHeap<i32> = DHeap::with_capacity(16);
+
+        let keys: Vec<_> = (0..8).map(|i| heap.insert(i)).collect();
+        for i in [1, 5, 6, 7] {
+            heap.remove(keys[i]);
+        }
+
+        assert_eq!(heap.trim(), 3);
+        assert_eq!(heap.size(), 6);
+        assert_eq!(heap.stats().free, 1);
+        assert_eq!(heap.stats().capacity, 16);
+
+        // Nothing moved, so the keys still resolve.
+        for i in [0, 2, 3, 4] {
+            assert_eq!(heap.get(keys[i]), Some(&(i as i32)));
+        }
+
+        // The hole is reused first, then the heap grows again.
+        assert_eq!(heap.insert(10).index(), 1);
+        assert_eq!(heap.insert(11).index(), 5);
+        assert_eq!(heap.trim(), 0);
+    }
+
+    #[test]
+    fn static_heap_fixed_capacity() {
+        let heap: StaticDHeap<String, 3> = StaticDHeap::empty();
+        assert_eq!(heap.capacity(), 3);
+
+        let a = heap.safe_new("a".to_string()).unwrap();
+        let b = heap.safe_new("b".to_string()).unwrap();
+        let mut c = heap.safe_new("c".to_string()).unwrap();
+        c.push('!');
+
+        match heap.safe_new("d".to_string()) {
+            Err(AllocError::CapacityExhausted(value)) => assert_eq!(value, "d"),
+            _ => panic!("expected the heap to be full"),
+        }
+
+        assert_eq!(b.into_inner(), "b");
+        drop(a);
+
+        let d = heap.safe_new("d".to_string()).unwrap();
+        let e = heap.safe_new("e".to_string()).unwrap();
+        assert!(heap.safe_new("f".to_string()).is_err());
+
+        assert_eq!((c.as_str(), d.as_str(), e.as_str()), ("c!", "d", "e"));
+        assert_eq!(heap.size(), 3);
+    }
+
+    #[test]
+    fn static_panicking_drop_frees_slot() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        struct Bomb;
+
+        impl Drop for Bomb {
+            fn drop(&mut self) {
+                panic!("boom");
+            }
+        }
+
+        let heap: StaticDHeap<Bomb, 1> = StaticDHeap::empty();
+        let bomb = heap.safe_new(Bomb).unwrap();
+        assert!(catch_unwind(AssertUnwindSafe(|| drop(bomb))).is_err());
+
+        // The slot went back to the free list while unwinding.
+        core::mem::forget(heap.safe_new(Bomb).unwrap());
+    }
+
+    #[test]
+    fn compact_slot_layout() {
+        use crate::slot::{Slot, Tag};
+        use core::mem::size_of;
+
+        // A slot is as small as the larger of the value and the free list link,
+        // and the state and generation of each slot fit in a 4 byte tag.
+        assert_eq!(size_of::<Tag>(), 4);
+        assert_eq!(size_of::<Slot<u8>>(), size_of::<usize>());
+        assert_eq!(size_of::<Slot<[u64; 4]>>(), 32);
+
+        // Narrow indices shrink the free list link, and the keys.
+        assert_eq!(size_of::<Slot<i32, u32>>(), 4);
+        assert_eq!(size_of::<Slot<u16, u16>>(), 2);
+        assert_eq!(size_of::<DKey<u16>>(), 8);
+    }
+
+    #[test]
+    fn narrow_index_exhaustion() {
+        let mut heap: DHeap<u32, u8> = DHeap::with_chunk_size_indexed(64);
+
+        // Indices 0 to 255 are addressable, and the last one is taken by the Edge.
+        let keys: Vec<_> = (0..255).map(|i| heap.insert(i)).collect();
+
+        match heap.safe_new(255) {
+            Err(AllocError::IndexExhausted(value)) => assert_eq!(value, 255),
+            _ => panic!("expected the index space to be exhausted"),
+        }
+
+        // Freeing a slot makes room again.
+        assert_eq!(heap.remove(keys[17]), Some(17));
+        let dbox = heap.safe_new(255).unwrap();
+        assert_eq!(*dbox, 255);
+        assert_eq!(heap.size(), 256);
+    }
+
+    #[test]
+    fn bulk_allocation() {
+        let heap = DHeap::with_capacity(4);
+
+        let first = heap.new(String::from("first"));
+        let reference: &String = &first;
+
+        // Free two slots in the middle, so the batch reuses them before it appends.
+        let holes = heap.alloc_many(["a", "b"].map(String::from));
+        drop(holes);
+
+        let boxes = heap.alloc_many((0..10).map(|i| i.to_string()));
+        let values: Vec<&str> = boxes.iter().map(|dbox| dbox.as_str()).collect();
+        assert_eq!(values, ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"]);
+
+        // Reserving the batch never moved the values that were already in the heap.
+        assert_eq!(reference, "first");
+        assert!(heap.chunk_size().is_some());
+        assert_eq!(heap.stats().live, 11);
+        assert_eq!(heap.size(), 12);
+    }
+
+    #[test]
+    fn try_extend_stops_when_full() {
+        let heap = DHeap::with_capacity(4);
+        let mut boxes = Vec::new();
+        let mut values = 0..10;
+
+        match heap.try_extend(&mut boxes, values.by_ref()) {
+            Err(AllocError::CapacityExhausted(value)) => assert_eq!(value, 4),
+            _ => panic!("expected the reserved memory to run out"),
+        }
+
+        // The boxes allocated before the failure are kept, and the rest of the batch can be resumed.
+        assert_eq!(
+            boxes.iter().map(|dbox| **dbox).collect::<Vec<_>>(),
+            [0, 1, 2, 3]
+        );
+        boxes.truncate(2);
+        assert!(heap.try_extend(&mut boxes, values.by_ref().take(2)).is_ok());
+        assert_eq!(
+            boxes.iter().map(|dbox| **dbox).collect::<Vec<_>>(),
+            [0, 1, 5, 6]
+        );
+        assert_eq!(values.next(), Some(7));
+    }
+
+    #[test]
+    fn reserve_between_phases() {
+        let mut heap = DHeap::with_capacity(4);
+        assert_eq!(heap.capacity(), 4);
+        assert_eq!(heap.remaining(), 4);
+
+        let keys: Vec<_> = (0..4)
+            .map(|i| heap.safe_new(i).unwrap().into_key())
+            .collect();
+        assert_eq!(heap.remaining(), 0);
+        assert!(heap.safe_new(4).is_err());
+
+        // A free slot counts towards the reservation.
+        heap.remove(keys[1]);
+        heap.reserve(10);
+        assert!(heap.remaining() >= 10);
+        assert_eq!(heap.chunk_size(), None);
+
+        let boxes: Vec<_> = (0..10).map(|i| heap.safe_new(i).unwrap()).collect();
+        assert_eq!(heap.stats().live, 13);
+        drop(boxes);
+
+        // Chunked heaps reserve whole chunks, and impossible requests are reported.
+        let mut heap: DHeap<u64> = DHeap::with_chunk_size(8);
+        assert!(heap.try_reserve(20).is_ok());
+        assert_eq!(heap.capacity(), 23);
+        assert_eq!(heap.remaining(), 23);
+        assert!(heap.try_reserve(usize::MAX).is_err());
+        assert_eq!(heap.capacity(), 23);
+    }
+
+    #[test]
+    // The hash and order of a DBox only depend on its value, not on the heap's interior mutability.
+    #[allow(clippy::mutable_key_type)]
+    fn dbox_forwards_traits() {
+        use alloc::collections::BTreeSet;
+        use std::collections::HashSet;
+
+        let heap = DHeap::with_chunk_size(4);
+        let a = heap.new(String::from("a"));
+        let b = heap.new(String::from("b"));
+
+        // A clone lives in its own slot of the same heap.
+        let c = a.clone();
+        assert_eq!(heap.stats().live, 3);
+        assert_ne!(format!("{:p}", a), format!("{:p}", c));
+
+        assert_eq!(format!("{:?} {}", a, b), "\"a\" b");
+        assert_eq!(a, c);
+        assert!(a < b);
+
+        let set: HashSet<_> = [&a, &b, &c].into_iter().collect();
+        assert_eq!(set.len(), 2);
+        let ordered: BTreeSet<_> = [b, a, c].into_iter().collect();
+        assert_eq!(
+            ordered.iter().map(|dbox| dbox.as_str()).collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn snapshot_round_trip() {
+        let mut heap: DHeap<String, u32> = DHeap::with_chunk_size_indexed(4);
+        let keys: Vec<_> = ["a", "b", "c", "d", "e"]
+            .map(|s| heap.insert(s.to_string()))
+            .into();
+        heap.remove(keys[1]);
+        heap.remove(keys[3]);
+
+        let json = serde_json::to_string(&heap.snapshot()).unwrap();
+        let saved = serde_json::to_string(&keys).unwrap();
+
+        let mut restored: DHeap<String, u32> = serde_json::from_str(&json).unwrap();
+        let keys: Vec<DKey<u32>> = serde_json::from_str(&saved).unwrap();
+        heap.clear();
+
+        assert_eq!(serde_json::to_string(&restored.snapshot()).unwrap(), json);
+        assert_eq!(restored.chunk_size(), Some(4));
+
+        // The keys resolve to the same slots, and the free list picks up where it left off.
+        assert_eq!(restored.get(keys[0]).map(String::as_str), Some("a"));
+        assert_eq!(restored.get(keys[1]), None);
+        assert_eq!(restored.get(keys[4]).map(String::as_str), Some("e"));
+        assert_eq!(restored.insert("f".to_string()).index(), keys[3].index());
+
+        // Snapshots with a broken free list are rejected.
+        let broken = json.replace("\"head\":3", "\"head\":0");
+        assert!(serde_json::from_str::<DHeap<String, u32>>(&broken).is_err());
+
+        // Leaked values are written out as well, and stay leaked after the restore.
+        restored.new(String::new()).leak();
+        let json = serde_json::to_string(&restored.snapshot()).unwrap();
+
+        let mut again: DHeap<String, u32> = serde_json::from_str(&json).unwrap();
+        assert_eq!(again.stats().leaked, 1);
+        assert_eq!(serde_json::to_string(&again.snapshot()).unwrap(), json);
+
+        // The restored values belong to the heap, which leaks them unless they are dropped.
+        restored.clear();
+        again.clear();
+        assert_eq!(again.stats().live, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn snapshot_rejects_corrupted_input() {
+        let restore = |json: &str| {
+            serde_json::from_str::<DHeap<u32>>(json)
+                .map(|_| ())
+                .map_err(|error| error.to_string())
+        };
+
+        // A free list that loops back on itself.
+        let cycle = r#"{"head":0,"chunk_size":null,"slots":[
+            {"Empty":{"generation":0,"next":1}},
+            {"Empty":{"generation":0,"next":0}},
+            {"Edge":{"generation":0}}
+        ]}"#;
+
+        // A free list that runs into a slot holding a value.
+        let used = r#"{"head":0,"chunk_size":null,"slots":[
+            {"Empty":{"generation":0,"next":1}},
+            {"Holding":{"generation":0,"value":7}},
+            {"Edge":{"generation":0}}
+        ]}"#;
+
+        let huge = format!(
+            r#"{{"head":0,"chunk_size":{},"slots":[{{"Edge":{{"generation":0}}}}]}}"#,
+            usize::MAX
+        );
+
+        assert!(restore(cycle).unwrap_err().contains("creating a cycle!"));
+        assert!(restore(used).unwrap_err().contains("slot 1"));
+        assert!(restore(&huge)
+            .unwrap_err()
+            .contains("chunk size out of range!"));
+    }
+
+    #[test]
+    fn validate_healthy_heap() {
+        let heap = DHeap::with_capacity(8);
+        let boxes: Vec<_> = (0..6).map(|i| heap.new(i)).collect();
+        let kept = boxes
+            .into_iter()
+            .filter(|dbox| **dbox % 2 == 0)
+            .collect::<Vec<_>>();
+
+        let report = heap.validate();
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!((report.holding, report.empty, report.linked), (3, 3, 3));
+        drop(kept);
+    }
+
+    #[test]
+    fn validate_reports_cycle() {
+        use crate::validate::DHeapIssue;
+
+        let mut heap: DHeap<u32> = DHeap::with_capacity(4);
+        let (first, second) = (heap.insert(0), heap.insert(1));
+        heap.remove(first);
+        heap.remove(second);
+
+        // The two free slots link to each other, instead of ending at the Edge.
+        heap.corrupt_link(0, 1);
+        let report = heap.validate();
+
+        assert_eq!(
+            report.issues,
+            [DHeapIssue::Cycle {
+                from: Some(0),
+                to: 1
+            }]
+        );
+        assert_eq!(
+            report.issues[0].to_string(),
+            "slot 0 links back to slot 1, creating a cycle!"
+        );
+    }
+
+    #[test]
+    #[cfg(any(debug_assertions, feature = "checked"))]
+    #[should_panic(expected = "slot 0 is Moved instead of Holding! [corrupted memory]")]
+    fn checked_deref_panics() {
+        use crate::validate::State;
+        use core::mem::ManuallyDrop;
+
+        let heap = DHeap::with_capacity(4);
+
+        // The box is never dropped, as it would find its slot in an unexpected state again.
+        let dbox = ManuallyDrop::new(heap.new(2));
+        heap.corrupt_state(0, State::Moved);
+        assert_eq!(**dbox, 2);
+    }
+
+    #[test]
+    #[cfg(any(debug_assertions, feature = "checked"))]
+    #[should_panic(expected = "slot 0 is Empty instead of Holding! [corrupted memory]")]
+    fn checked_static_deref_panics() {
+        use crate::validate::State;
+        use core::mem::ManuallyDrop;
+
+        let heap: StaticDHeap<i32, 2> = StaticDHeap::empty();
+        let mut dbox = ManuallyDrop::new(heap.safe_new(1).unwrap());
+        heap.corrupt_state(0, State::Empty);
+        **dbox += 1;
+    }
+
+    #[test]
+    #[cfg(any(debug_assertions, feature = "checked"))]
+    fn corrupted_static_heap_is_poisoned() {
+        use crate::validate::State;
+
+        let mut heap: StaticDHeap<i32, 4> = StaticDHeap::empty();
+        let first = heap.safe_new(1).unwrap();
+        drop(heap.safe_new(2).unwrap());
+
+        // The head of the free list points at a slot that is holding a value.
+        heap.corrupt_state(1, State::Holding);
+        assert!(matches!(heap.safe_new(3), Err(AllocError::Poisoned(3))));
+
+        // Boxes can still be used, but nothing new is allocated until the poison is cleared.
+        assert!(heap.is_poisoned());
+        heap.corrupt_state(1, State::Empty);
+        assert_eq!(first.into_inner(), 1);
+        assert!(matches!(heap.safe_new(4), Err(AllocError::Poisoned(4))));
+
+        heap.clear_poison();
+        assert_eq!(*heap.safe_new(5).unwrap(), 5);
+    }
+
+    #[test]
+    #[cfg(feature = "allocator-api2")]
+    fn heap_as_allocator() {
+        use allocator_api2::{boxed::Box, vec::Vec};
+
+        let mut heap: DHeap<u64> = DHeap::with_capacity(4);
+        let key = heap.insert(1);
+
+        {
+            // Single values are served from the slots of the heap.
+            let a = Box::new_in(2u64, &heap);
+            let b = Box::new_in(3u32, &heap);
+            assert_eq!((*a, *b), (2, 3));
+            assert_eq!(heap.stats().lent, 2);
+
+            // Buffers of more than one value fall back to the global allocator.
+            let mut values = Vec::new_in(&heap);
+            values.extend(0..100u64);
+            assert_eq!(values.iter().sum::<u64>(), 4950);
+            assert_eq!(heap.stats().lent, 2);
+
+            // Layouts that need a stricter alignment do not fit into a slot either.
+            let aligned = Box::new_in(0u128, &heap);
+            assert_eq!(*aligned, 0);
+            assert_eq!(heap.stats().lent, 2);
+        }
+
+        // Deallocated blocks go back to the free list.
+        let stats = heap.stats();
+        assert_eq!((stats.live, stats.lent, stats.free), (1, 0, 2));
+        assert!(heap.validate().is_ok());
+        assert_eq!(heap.iter().collect::<Vec<_>>(), [&1]);
+        assert_eq!(heap.remove(key), Some(1));
+    }
+
+    #[test]
+    fn shared_handles() {
+        let heap = DHeap::with_capacity(4);
+
+        let a = heap.new_rc(String::from("shared"));
+        let b = a.clone();
+        let weak = DRc::downgrade(&a);
+
+        assert!(DRc::ptr_eq(&a, &b));
+        assert_eq!((DRc::strong_count(&a), DRc::weak_count(&a)), (2, 1));
+        assert_eq!(
+            weak.upgrade().as_deref().map(String::as_str),
+            Some("shared")
+        );
+
+        // A handle that is not the last one cannot take the value.
+        let b = DRc::try_unwrap(b).unwrap_err();
+        drop(a);
+        assert_eq!(weak.strong_count(), 1);
+        drop(b);
+
+        // The slot is freed with the last strong handle, and reused by the next value.
+        assert_eq!(heap.stats().live, 0);
+        let reused = heap.new_rc(String::from("reused"));
+        assert!(weak.upgrade().is_none());
+        assert_eq!(weak.strong_count(), 0);
+        drop(weak);
+
+        assert_eq!(DRc::weak_count(&reused), 0);
+        assert_eq!(DRc::try_unwrap(reused).unwrap(), "reused");
+        assert!(heap.validate().is_ok());
+    }
+
+    #[test]
+    fn panicking_drop_frees_slot() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        struct Bomb;
+
+        impl Drop for Bomb {
+            fn drop(&mut self) {
+                panic!("boom");
+            }
+        }
+
+        let heap = DHeap::with_capacity(4);
+        let bomb = heap.new(Bomb);
+
+        assert!(catch_unwind(AssertUnwindSafe(|| drop(bomb))).is_err());
+
+        // The slot went back to the free list while unwinding.
+        let stats = heap.stats();
+        assert_eq!((stats.live, stats.moved, stats.free), (0, 0, 1));
+        assert!(heap.validate().is_ok());
+        assert!(!heap.is_poisoned());
+
+        let dbox = heap.new(Bomb);
+        assert!(catch_unwind(AssertUnwindSafe(|| dbox.try_free())).is_err());
+        assert_eq!(heap.stats().free, 1);
+    }
+
+    #[test]
+    #[cfg(not(feature = "debug-checks"))]
+    fn corrupted_heap_is_poisoned() {
+        let mut heap: DHeap<u32> = DHeap::with_capacity(4);
+        let key = heap.insert(0);
+        heap.insert(7);
+        heap.remove(key);
+
+        // The free list runs into a slot that is holding a value.
+        heap.corrupt_link(0, 1);
+        let first = heap.safe_new(1).unwrap();
+
+        match heap.safe_new(2) {
+            Err(AllocError::Poisoned(value)) => assert_eq!(value, 2),
+            _ => panic!("expected the heap to be poisoned"),
+        }
+
+        // Boxes can still be freed, but nothing new is allocated until the poison is cleared.
+        assert!(heap.is_poisoned());
+        assert_eq!(first.try_into_inner(), Ok(1));
+        assert!(matches!(heap.safe_new(3), Err(AllocError::Poisoned(3))));
+
+        heap.clear_poison();
+        assert_eq!(*heap.safe_new(4).unwrap(), 4);
+    }
+
+    #[test]
+    fn leak_policy_drops_forgotten_values() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Counted;
+
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let heap = DHeap::with_capacity(4);
+        core::mem::forget(heap.new(Counted));
+        heap.insert(Counted);
+        drop(heap);
+
+        // By default, the values are leaked.
+        assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+
+        let mut heap = DHeap::with_capacity(4);
+        heap.set_drop_on_leak();
+        core::mem::forget(heap.new(Counted));
+        heap.insert(Counted);
+        drop(heap);
+
+        assert_eq!(DROPS.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn clear_drops_borrowing_values() {
+        use std::cell::Cell;
+
+        struct Counted<'a>(&'a Cell<usize>);
+
+        impl Drop for Counted<'_> {
+            fn drop(&mut self) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+
+        let drops = Cell::new(0);
+        let mut heap = DHeap::with_capacity(4);
+
+        let key = heap.insert(Counted(&drops));
+        heap.insert(Counted(&drops));
+        core::mem::forget(heap.new(Counted(&drops)));
+        heap.new(Counted(&drops)).leak();
+
+        // Leaked values stay where they are.
+        heap.clear();
+        assert_eq!(drops.get(), 3);
+        assert!(!heap.contains_key(key));
+
+        let stats = heap.stats();
+        assert_eq!((stats.live, stats.free, stats.leaked), (0, 3, 1));
+        assert!(heap.validate().is_ok());
+
+        heap.insert(Counted(&drops));
+        heap.clear();
+        assert_eq!(drops.get(), 4);
+    }
+
+    #[test]
+    fn leak_policy_reports_leaks() {
+        use crate::leak::*;
+        use std::sync::Mutex;
+
+        static LEAKS: Mutex<Vec<Leak>> = Mutex::new(Vec::new());
+
+        let mut heap = DHeap::with_capacity(4);
+        heap.set_leak_policy(LeakPolicy::Report(|leak| LEAKS.lock().unwrap().push(leak)));
+
+        let kept = heap.new(1);
+        let key = heap.insert(2);
+        #[cfg(feature = "track-allocations")]
+        let line = line!() - 2;
+        drop(kept);
+        drop(heap);
+
+        // Values that borrow something can be reported as well.
+        let value = 3;
+        let mut heap: DHeap<&i32> = DHeap::with_capacity(4);
+        heap.set_leak_policy(LeakPolicy::Report(|leak| LEAKS.lock().unwrap().push(leak)));
+        heap.insert(&value);
+        drop(heap);
+
+        let leaks = LEAKS.lock().unwrap();
+        assert_eq!(leaks.len(), 2);
+        assert_eq!((leaks[0].index, leaks[0].generation), (1, key.generation()));
+        assert_eq!(leaks[1].index, 0);
+
+        #[cfg(feature = "track-allocations")]
+        {
+            let site = leaks[0].site.unwrap();
+            assert_eq!((site.file(), site.line()), (file!(), line));
+        }
+    }
+
+    #[test]
+    fn in_place_construction() {
+        let heap: DHeap<[u64; 512]> = DHeap::with_chunk_size(4);
+
+        let zeros = heap.alloc_with(|| [0; 512]);
+        assert_eq!(zeros[511], 0);
+
+        let mut uninit = heap.alloc_uninit();
+        let array = uninit.as_mut_ptr() as *mut u64;
+        (0..512).for_each(|i| unsafe { array.add(i).write(i as u64) });
+        let counting = unsafe { uninit.assume_init() };
+        assert_eq!(counting[511], 511);
+
+        // Failed and abandoned initializations give their slot back.
+        let failed = heap.try_alloc_with(|| Err::<[u64; 512], _>("no"));
+        assert!(matches!(failed, Err("no")));
+        drop(heap.alloc_uninit());
+
+        let stats = heap.stats();
+        assert_eq!((stats.live, stats.lent, stats.free), (2, 0, 1));
+        assert_eq!(
+            *heap.try_alloc_with(|| Ok::<_, ()>([1; 512])).unwrap(),
+            [1; 512]
+        );
+        assert!(heap.validate().is_ok());
+    }
+
+    #[test]
+    fn dbox_value_manipulation() {
+        let heap = DHeap::with_capacity(8);
+        let mut a = heap.new(String::from("a"));
+        let mut b = heap.new(String::from("b"));
+
+        assert_eq!(a.replace(String::from("c")), "a");
+        assert_eq!(b.take(), "b");
+        b.map_in_place(|s| s + "d");
+
+        let (slot_a, slot_b) = (&*a as *const String, &*b as *const String);
+        DBox::swap(&mut a, &mut b);
+        assert_eq!((a.as_str(), b.as_str()), ("d", "c"));
+        assert_eq!(
+            (&*a as *const String, &*b as *const String),
+            (slot_b, slot_a)
+        );
+
+        let leaked = b.leak();
+        leaked.push('!');
+        assert_eq!(core::mem::take(leaked), "c!");
+
+        // The leaked slot is never handed out again.
+        drop(a);
+        assert_eq!(*heap.new(String::from("e")), "e");
+        assert_ne!(&*heap.new(String::from("f")) as *const String, slot_a);
+
+        let stats = heap.stats();
+        assert_eq!((stats.live, stats.leaked, stats.free), (0, 1, 1));
+        assert_eq!(heap.validate().leaked, 1);
+    }
+
+    #[test]
+    fn owned_handles_keep_heap_alive() {
+        use std::any::Any;
+        use std::rc::Rc;
+
+        struct OwnedNode {
+            value: i32,
+            next: Option<OwnedDBox<OwnedNode>>,
+        }
+
+        let heap = Rc::new(DHeap::with_chunk_size(4));
+
+        let mut list: Option<OwnedDBox<OwnedNode>> = None;
+        for value in 0..10 {
+            let next = list.take();
+            list = Some(OwnedDBox::new(heap.clone(), OwnedNode { value, next }));
+        }
+
+        // The boxes keep the heap alive on their own, and can be stored as trait objects.
+        drop(heap);
+        let list: Box<dyn Any> = Box::new(list.unwrap());
+        let mut node = *list.downcast::<OwnedDBox<OwnedNode>>().unwrap();
+
+        let heap = OwnedDBox::heap(&node).clone();
+        assert_eq!((heap.stats().live, Rc::strong_count(&heap)), (10, 11));
+
+        let mut values = vec![];
+        loop {
+            values.push(node.value);
+            match node.into_inner().next {
+                Some(next) => node = next,
+                None => break,
+            }
+        }
+
+        assert_eq!(values, (0..10).rev().collect::<Vec<_>>());
+        assert_eq!((heap.stats().live, Rc::strong_count(&heap)), (0, 1));
+    }
 }
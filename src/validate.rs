@@ -0,0 +1,147 @@
+// validate.rs --- integrity checks for the dense heap.
+
+// Copyright (c) 2023 Sam Belliveau. All rights reserved.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+pub use crate::slot::State;
+
+/// A DHeapReport describes the integrity of a DHeap, returned by `DHeap::validate()`.
+///
+/// It counts the slots in each state, and lists every problem that was found
+/// while walking the slots and the free list.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct DHeapReport {
+    /// The number of slots that are holding a value, owned either by a handle or by the heap.
+    pub holding: usize,
+
+    /// The number of freed slots.
+    pub empty: usize,
+
+    /// The number of slots whose value was moved out with `into_inner()`.
+    pub moved: usize,
+
+    /// The number of slots that were taken without a value.
+    pub lent: usize,
+
+    /// The number of slots whose value was leaked with `DBox::leak()`.
+    pub leaked: usize,
+
+    /// The number of freed slots that can be reached from the head of the free list.
+    pub linked: usize,
+
+    /// Every problem that was found, in the order it was found in.
+    pub issues: Vec<DHeapIssue>,
+}
+
+impl DHeapReport {
+    /// Returns true if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// A DHeapIssue is a single broken invariant of a DHeap, found by `DHeap::validate()`.
+///
+/// A link of the free list starts either at the head, in which case `from` is `None`,
+/// or at the freed slot `from`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DHeapIssue {
+    /// The last slot of the heap is not the `Edge`.
+    MissingEdge { state: State },
+
+    /// An `Edge` was found before the last slot of the heap.
+    MisplacedEdge { index: usize },
+
+    /// A link of the free list points past the end of the heap.
+    LinkOutOfBounds { from: Option<usize>, to: usize },
+
+    /// A link of the free list points at a slot that is not free.
+    LinkToUsedSlot {
+        from: Option<usize>,
+        to: usize,
+        state: State,
+    },
+
+    /// The free list comes back around to a slot it has already visited.
+    Cycle { from: Option<usize>, to: usize },
+
+    /// A freed slot is not part of the free list, so it is never reused.
+    Unlinked { index: usize },
+
+    /// The counters of `DHeap::stats()` do not match the slots.
+    StatsMismatch,
+}
+
+impl fmt::Display for DHeapIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            DHeapIssue::MissingEdge { state } => {
+                write!(f, "the last slot is {:?} instead of the Edge!", state)
+            }
+            DHeapIssue::MisplacedEdge { index } => {
+                write!(f, "slot {} is an Edge before the end of the heap!", index)
+            }
+            DHeapIssue::LinkOutOfBounds { from, to } => {
+                write!(
+                    f,
+                    "{} links to slot {}, which is out of bounds!",
+                    Source(from),
+                    to
+                )
+            }
+            DHeapIssue::LinkToUsedSlot { from, to, state } => {
+                write!(
+                    f,
+                    "{} links to slot {}, which is {:?}!",
+                    Source(from),
+                    to,
+                    state
+                )
+            }
+            DHeapIssue::Cycle { from, to } => {
+                write!(
+                    f,
+                    "{} links back to slot {}, creating a cycle!",
+                    Source(from),
+                    to
+                )
+            }
+            DHeapIssue::Unlinked { index } => {
+                write!(f, "slot {} is free, but not in the free list!", index)
+            }
+            DHeapIssue::StatsMismatch => write!(f, "the statistics do not match the slots!"),
+        }
+    }
+}
+
+// The start of a link in the free list.
+struct Source(Option<usize>);
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(index) => write!(f, "slot {}", index),
+            None => write!(f, "the head"),
+        }
+    }
+}